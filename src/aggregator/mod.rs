@@ -1,2 +1,2 @@
-pub mod channel;
+pub mod mpsc;
 pub mod mutex;
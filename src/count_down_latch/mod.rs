@@ -1 +1,2 @@
+pub mod mpsc;
 pub mod mutex;
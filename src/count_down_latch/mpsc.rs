@@ -0,0 +1,218 @@
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{RecvTimeoutError, Sender, channel};
+use std::thread;
+use std::thread::{JoinHandle, sleep};
+use std::time::Duration;
+
+const N_THREADS: usize = 10;
+
+enum Request {
+    CountDown(usize),
+    Await(usize, Sender<()>), // answered once the counter reaches zero, the id is for Cancel
+    Cancel(usize),            // a waiter timed out, its sender is dropped unless already answered
+    Count(Sender<usize>),
+    Reset,
+    #[cfg(test)]
+    Waiters(Sender<usize>),
+}
+
+// the counter lives in a daemon thread, which releases the waiters by answering them
+pub struct CountDownLatch {
+    sender: Option<Sender<Request>>,
+    thread: Option<JoinHandle<()>>,
+    next_id: AtomicUsize,
+}
+
+impl CountDownLatch {
+    pub fn new(counter: usize) -> Arc<Self> {
+        Self::build(counter, false)
+    }
+
+    // reopens by itself: the waiters are released and the counter goes back to its initial value
+    pub fn cyclic(counter: usize) -> Arc<Self> {
+        Self::build(counter, true)
+    }
+
+    fn build(initial: usize, cyclic: bool) -> Arc<Self> {
+        assert!(initial > 0, "The counter has to be strictly positive");
+        let (snd, rx) = channel::<Request>();
+        let thread = thread::spawn(move || {
+            let mut counter = initial;
+            let mut waiters = HashMap::<usize, Sender<()>>::new();
+            while let Ok(request) = rx.recv() {
+                match request {
+                    Request::CountDown(n) if counter > 0 && n > 0 => {
+                        counter = counter.saturating_sub(n);
+                        if counter == 0 {
+                            waiters.drain().for_each(|(_, w)| w.send(()).unwrap());
+                            if cyclic {
+                                counter = initial;
+                            }
+                        }
+                    }
+                    Request::CountDown(_) => {}
+                    Request::Await(_, w) if counter == 0 => w.send(()).unwrap(),
+                    Request::Await(id, w) => {
+                        waiters.insert(id, w);
+                    }
+                    Request::Cancel(id) => {
+                        waiters.remove(&id);
+                    }
+                    Request::Count(count_snd) => count_snd.send(counter).unwrap(),
+                    Request::Reset => counter = initial,
+                    #[cfg(test)]
+                    Request::Waiters(len_snd) => len_snd.send(waiters.len()).unwrap(),
+                }
+            }
+        });
+        Arc::new(CountDownLatch {
+            sender: Some(snd),
+            thread: Some(thread),
+            next_id: AtomicUsize::new(0),
+        })
+    }
+
+    fn request(&self, request: Request) {
+        self.sender.as_ref().unwrap().send(request).unwrap();
+    }
+
+    pub fn await_(&self) {
+        self.wait_until(None);
+    }
+
+    // false if the counter didn't reach zero in time
+    pub fn await_timeout(&self, timeout: Duration) -> bool {
+        self.wait_until(Some(timeout))
+    }
+
+    fn wait_until(&self, timeout: Option<Duration>) -> bool {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (snd, rx) = channel();
+        self.request(Request::Await(id, snd));
+        match timeout.map(|timeout| rx.recv_timeout(timeout)) {
+            Some(Err(RecvTimeoutError::Timeout)) => {
+                self.request(Request::Cancel(id));
+                // either the latch opened just before, or the sender is dropped
+                rx.recv().is_ok()
+            }
+            Some(result) => result.is_ok(),
+            None => rx.recv().is_ok(),
+        }
+    }
+
+    pub fn count_down(&self) {
+        self.request(Request::CountDown(1));
+    }
+
+    // never goes below zero, whatever n is
+    pub fn count_down_by(&self, n: usize) {
+        self.request(Request::CountDown(n));
+    }
+
+    pub fn count(&self) -> usize {
+        let (snd, rx) = channel();
+        self.request(Request::Count(snd));
+        rx.recv().unwrap()
+    }
+
+    // back to the initial value, for a new phase; threads already waiting keep waiting
+    pub fn reset(&self) {
+        self.request(Request::Reset);
+    }
+}
+
+impl Drop for CountDownLatch {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        self.thread.take().unwrap().join().unwrap();
+    }
+}
+
+pub fn test() {
+    let latch = CountDownLatch::cyclic(N_THREADS / 2);
+
+    let mut handles = vec![];
+
+    for i in 0..N_THREADS {
+        handles.push(thread::spawn({
+            let latch = latch.clone();
+            move || {
+                let time = rand::rng().random_range(1..4);
+                sleep(Duration::from_secs(time));
+                if i % 2 == 0 {
+                    println!("Thread {i} is waiting...");
+                    latch.await_();
+                    println!("Thread {i} returns!");
+                } else {
+                    println!("Thread {i} counting down...");
+                    latch.count_down();
+                }
+            }
+        }))
+    }
+
+    for h in handles {
+        h.join().unwrap();
+    }
+    println!("The latch is back to {}", latch.count());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn await_timeout_tells_whether_the_latch_opened() {
+        let latch = CountDownLatch::new(3);
+        assert!(!latch.await_timeout(Duration::from_millis(10)));
+        latch.count_down_by(2);
+        assert_eq!(latch.count(), 1);
+        let waiter = thread::spawn({
+            let latch = latch.clone();
+            move || latch.await_timeout(Duration::from_secs(10))
+        });
+        latch.count_down_by(5);
+        assert!(waiter.join().unwrap());
+        assert_eq!(latch.count(), 0);
+    }
+
+    #[test]
+    fn reset_reuses_the_latch() {
+        let latch = CountDownLatch::new(1);
+        latch.count_down();
+        latch.await_();
+        latch.reset();
+        assert_eq!(latch.count(), 1);
+        assert!(!latch.await_timeout(Duration::from_millis(10)));
+        latch.count_down();
+        assert!(latch.await_timeout(Duration::ZERO));
+    }
+
+    #[test]
+    fn a_waiter_that_timed_out_is_forgotten() {
+        let latch = CountDownLatch::new(1);
+        (0..3).for_each(|_| assert!(!latch.await_timeout(Duration::from_millis(1))));
+        let (len_snd, len_rx) = channel();
+        latch.request(Request::Waiters(len_snd));
+        assert_eq!(len_rx.recv().unwrap(), 0);
+    }
+
+    #[test]
+    fn a_cyclic_latch_releases_every_phase() {
+        let latch = CountDownLatch::cyclic(2);
+        for _ in 0..3 {
+            let waiter = thread::spawn({
+                let latch = latch.clone();
+                move || latch.await_timeout(Duration::from_secs(10))
+            });
+            sleep(Duration::from_millis(20)); // lets the waiter join the current phase
+            latch.count_down_by(1);
+            latch.count_down_by(1);
+            assert!(waiter.join().unwrap());
+            assert_eq!(latch.count(), 2);
+        }
+    }
+}
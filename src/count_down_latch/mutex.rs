@@ -6,40 +6,117 @@ use std::time::Duration;
 
 const N_THREADS: usize = 10;
 
+struct State {
+    counter: usize,
+    initial: usize,
+    generation: usize, // bumped every time the counter reaches zero
+    cyclic: bool,      // a cyclic latch starts over as soon as it opens
+}
+
 pub struct CountDownLatch {
     cv: Condvar,
-    counter: Mutex<usize>,
+    state: Mutex<State>,
 }
 
 impl CountDownLatch {
     pub fn new(counter: usize) -> Arc<Self> {
-        return Arc::new(CountDownLatch {
+        Self::build(counter, false)
+    }
+
+    // reopens by itself: the waiters are released and the counter goes back to its initial value
+    pub fn cyclic(counter: usize) -> Arc<Self> {
+        Self::build(counter, true)
+    }
+
+    fn build(counter: usize, cyclic: bool) -> Arc<Self> {
+        assert!(counter > 0, "The counter has to be strictly positive");
+        Arc::new(CountDownLatch {
             cv: Condvar::new(),
-            counter: Mutex::new(counter),
-        });
+            state: Mutex::new(State {
+                counter,
+                initial: counter,
+                generation: 0,
+                cyclic,
+            }),
+        })
     }
 
     pub fn awaiting(&self, index: usize) {
-        let lock = self.counter.lock().unwrap();
         println!("Thread {} is waiting...", index);
-        drop(self.cv.wait_while(lock, |l| *l > 0).unwrap());
+        self.wait_until(None);
         println!("Thread {} returns!", index);
     }
 
+    // false if the counter didn't reach zero in time
+    pub fn await_timeout(&self, timeout: Duration) -> bool {
+        self.wait_until(Some(timeout))
+    }
+
+    fn wait_until(&self, timeout: Option<Duration>) -> bool {
+        let lock = self.state.lock().unwrap();
+        let generation = lock.generation;
+        let waiting = |s: &mut State| s.counter > 0 && s.generation == generation;
+        let lock = match timeout {
+            Some(timeout) => {
+                self.cv
+                    .wait_timeout_while(lock, timeout, waiting)
+                    .unwrap()
+                    .0
+            }
+            None => self.cv.wait_while(lock, waiting).unwrap(),
+        };
+        lock.counter == 0 || lock.generation != generation
+    }
+
     pub fn count_down(&self, index: usize) {
         println!("Thread {} counting down...", index);
-        let mut lock = self.counter.lock().unwrap();
+        let mut lock = self.state.lock().unwrap();
 
-        if *lock == 0 {
+        if lock.counter == 0 {
             return;
         }
 
-        *lock -= 1;
-        println!("Thread {} decreased from {} to {}", index, *lock + 1, *lock);
-        if *lock == 0 {
-            self.cv.notify_all()
+        lock.counter -= 1;
+        println!(
+            "Thread {} decreased from {} to {}",
+            index,
+            lock.counter + 1,
+            lock.counter
+        );
+        if lock.counter == 0 {
+            self.open(&mut lock);
         }
     }
+
+    // never goes below zero, whatever n is
+    pub fn count_down_by(&self, n: usize) {
+        let mut lock = self.state.lock().unwrap();
+        if lock.counter == 0 || n == 0 {
+            return;
+        }
+        lock.counter = lock.counter.saturating_sub(n);
+        if lock.counter == 0 {
+            self.open(&mut lock);
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.state.lock().unwrap().counter
+    }
+
+    // back to the initial value, for a new phase; threads already waiting keep waiting
+    pub fn reset(&self) {
+        let mut lock = self.state.lock().unwrap();
+        lock.counter = lock.initial;
+    }
+
+    fn open(&self, state: &mut State) {
+        state.generation += 1;
+        if state.cyclic {
+            state.counter = state.initial;
+        }
+        self.cv.notify_all();
+    }
 }
 
 pub fn test() {
@@ -66,3 +143,49 @@ pub fn test() {
         h.join().unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn await_timeout_tells_whether_the_latch_opened() {
+        let latch = CountDownLatch::new(3);
+        assert!(!latch.await_timeout(Duration::from_millis(10)));
+        latch.count_down_by(2);
+        assert_eq!(latch.count(), 1);
+        let waiter = thread::spawn({
+            let latch = latch.clone();
+            move || latch.await_timeout(Duration::from_secs(10))
+        });
+        latch.count_down_by(5);
+        assert!(waiter.join().unwrap());
+        assert_eq!(latch.count(), 0);
+    }
+
+    #[test]
+    fn reset_reuses_the_latch() {
+        let latch = CountDownLatch::new(1);
+        latch.count_down_by(1);
+        assert!(latch.await_timeout(Duration::ZERO));
+        latch.reset();
+        assert_eq!(latch.count(), 1);
+        assert!(!latch.await_timeout(Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn a_cyclic_latch_releases_every_phase() {
+        let latch = CountDownLatch::cyclic(2);
+        for _ in 0..3 {
+            let waiter = thread::spawn({
+                let latch = latch.clone();
+                move || latch.await_timeout(Duration::from_secs(10))
+            });
+            sleep(Duration::from_millis(20)); // lets the waiter join the current phase
+            latch.count_down_by(1);
+            latch.count_down_by(1);
+            assert!(waiter.join().unwrap());
+            assert_eq!(latch.count(), 2);
+        }
+    }
+}
@@ -1,43 +1,59 @@
-use std::cmp::Ordering;
-use std::cmp::min;
-use std::collections::{BinaryHeap, VecDeque};
-use std::sync::mpsc::{RecvTimeoutError, Sender, channel};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{RecvTimeoutError, Sender, TryRecvError, channel};
 use std::thread::{JoinHandle, spawn};
 use std::time::{Duration, Instant};
 
 const ONE_WEEK: Duration = Duration::from_secs(60 * 60 * 24 * 7);
 
-struct Timed<T>(Instant, T);
+type Taken<T> = Result<T, RecvTimeoutError>;
 
-impl<T> PartialEq for Timed<T> {
-    fn eq(&self, other: &Self) -> bool {
-        self.cmp(other) == Ordering::Equal
-    }
+enum Op<T: Send> {
+    Offer(usize, Instant, T), // the id is chosen by the caller, offer doesn't wait for an answer
+    Take(Option<Instant>, Sender<Taken<T>>), // the worker answers Timeout once past the limit
+    Length(Sender<usize>),
+    Peek(Sender<Option<Instant>>),
+    Cancel(usize, Sender<Option<T>>),
+    Reschedule(usize, Instant, Sender<bool>),
+    Close,
+    Stop, // the handles hold senders too, the channel never disconnects by itself
 }
 
-impl<T> Eq for Timed<T> {}
-
-impl<T> PartialOrd for Timed<T> {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
-    }
+struct Items<T> {
+    items: HashMap<usize, (Instant, T)>,
+    // one entry per offer or reschedule, the stale ones are dropped when they reach the top
+    deadlines: BinaryHeap<Reverse<(Instant, usize)>>,
 }
 
-impl<T> Ord for Timed<T> {
-    fn cmp(&self, other: &Self) -> Ordering {
-        self.0.cmp(&other.0).reverse()
+impl<T> Items<T> {
+    fn nearest(&mut self) -> Option<(Instant, usize)> {
+        while let Some(&Reverse((deadline, id))) = self.deadlines.peek() {
+            if self.items.get(&id).is_some_and(|(d, _)| *d == deadline) {
+                return Some((deadline, id));
+            }
+            self.deadlines.pop();
+        }
+        None
     }
-}
 
-enum Op<T: Send> {
-    Offer(Instant, T),
-    Take(Sender<Option<T>>),
-    Length(Sender<usize>),
+    fn pop_due(&mut self) -> Option<T> {
+        match self.nearest()? {
+            (deadline, id) if deadline <= Instant::now() => {
+                self.deadlines.pop();
+                self.items.remove(&id).map(|(_, item)| item)
+            }
+            _ => None,
+        }
+    }
 }
 
 pub struct DelayedQueue<T: Send> {
     op_sender: Option<Sender<Op<T>>>,
     thread: Option<JoinHandle<()>>,
+    next_id: AtomicUsize,
+    #[cfg(test)]
+    timeouts: std::sync::Arc<AtomicUsize>, // how many times the worker woke up with nothing to do
 }
 
 impl<T> DelayedQueue<T>
@@ -47,80 +63,146 @@ where
     pub fn new() -> Self {
         let (op_sender, op_rx) = channel::<Op<T>>();
         let op_sender = Some(op_sender);
+        #[cfg(test)]
+        let timeouts = std::sync::Arc::new(AtomicUsize::new(0));
+        #[cfg(test)]
+        let timeouts_clone = timeouts.clone();
         Self {
             op_sender,
             thread: Some(spawn(move || {
-                let mut take_queue = VecDeque::<Sender<Option<T>>>::new();
-                let mut ready_items = VecDeque::<T>::new();
-                let mut timed_items = BinaryHeap::<Timed<T>>::new();
-                let mut next_check = Instant::now() + ONE_WEEK;
+                let mut take_queue = VecDeque::<(Option<Instant>, Sender<Taken<T>>)>::new();
+                let mut queue = Items {
+                    items: HashMap::new(),
+                    deadlines: BinaryHeap::new(),
+                };
+                let mut closed = false;
                 loop {
-                    match op_rx.recv_timeout(next_check - Instant::now()) {
-                        Ok(Op::Offer(t, item)) => {
-                            if t < Instant::now() && take_queue.len() > 0 {
-                                take_queue.pop_front().unwrap().send(Some(item)).unwrap();
-                            } else if t < Instant::now() && take_queue.len() == 0 {
-                                ready_items.push_back(item);
-                            } else {
-                                next_check = min(next_check, t);
-                                timed_items.push(Timed(t, item));
-                            }
-                        }
-                        Ok(Op::Take(item_snd)) => {
-                            if ready_items.len() > 0 {
-                                item_snd
-                                    .send(Some(ready_items.pop_front().unwrap()))
-                                    .unwrap();
-                            } else if timed_items.len() - take_queue.len() > 0 {
-                                take_queue.push_back(item_snd);
-                            } else {
-                                item_snd.send(None).unwrap();
+                    // the next item to be due or the next taker to give up, whichever comes first;
+                    // without takers a due item would wake up the worker over and over again
+                    let next_check = queue
+                        .nearest()
+                        .filter(|_| !take_queue.is_empty())
+                        .map(|(deadline, _)| deadline)
+                        .into_iter()
+                        .chain(take_queue.iter().filter_map(|(limit, _)| *limit))
+                        .min()
+                        .unwrap_or(Instant::now() + ONE_WEEK);
+                    match op_rx.recv_timeout(next_check.saturating_duration_since(Instant::now())) {
+                        Ok(Op::Offer(id, t, item)) => {
+                            if !closed {
+                                queue.items.insert(id, (t, item));
+                                queue.deadlines.push(Reverse((t, id)));
                             }
                         }
+                        Ok(Op::Take(limit, item_snd)) => take_queue.push_back((limit, item_snd)),
                         Ok(Op::Length(len_snd)) => {
-                            len_snd
-                                .send(ready_items.len() + timed_items.len() - take_queue.len())
-                                .unwrap();
+                            len_snd.send(queue.items.len()).unwrap();
                         }
-                        Err(RecvTimeoutError::Timeout) => {
-                            if timed_items.len() == 0 {
-                                next_check += ONE_WEEK;
-                                return;
-                            }
-                            let Timed(_, item) = timed_items.pop().unwrap();
-                            if take_queue.len() > 0 {
-                                take_queue.pop_front().unwrap().send(Some(item)).unwrap();
-                            } else {
-                                ready_items.push_back(item);
-                            }
+                        Ok(Op::Peek(deadline_snd)) => {
+                            let deadline = queue.nearest().map(|(deadline, _)| deadline);
+                            deadline_snd.send(deadline).unwrap();
+                        }
+                        Ok(Op::Cancel(id, item_snd)) => {
+                            let item = queue.items.remove(&id).map(|(_, item)| item);
+                            let _ = item_snd.send(item);
+                        }
+                        Ok(Op::Reschedule(id, t, done_snd)) => {
+                            let found = match queue.items.get_mut(&id) {
+                                Some((deadline, _)) => {
+                                    *deadline = t;
+                                    queue.deadlines.push(Reverse((t, id)));
+                                    true
+                                }
+                                None => false,
+                            };
+                            let _ = done_snd.send(found);
                         }
-                        Err(RecvTimeoutError::Disconnected) => {
+                        Ok(Op::Close) => closed = true,
+                        Ok(Op::Stop) | Err(RecvTimeoutError::Disconnected) => {
                             break;
                         }
+                        Err(RecvTimeoutError::Timeout) => {
+                            #[cfg(test)]
+                            timeouts_clone.fetch_add(1, Ordering::Relaxed);
+                        }
                     }
+                    // the items due go to the takers in arrival order
+                    while !take_queue.is_empty() {
+                        let Some(item) = queue.pop_due() else {
+                            break;
+                        };
+                        let (_, item_snd) = take_queue.pop_front().unwrap();
+                        item_snd.send(Ok(item)).unwrap();
+                    }
+                    let now = Instant::now();
+                    take_queue.retain(|(limit, item_snd)| {
+                        let error = match limit {
+                            _ if closed => RecvTimeoutError::Disconnected,
+                            Some(limit) if *limit <= now => RecvTimeoutError::Timeout,
+                            _ => return true,
+                        };
+                        item_snd.send(Err(error)).unwrap();
+                        false
+                    });
                 }
             })),
+            next_id: AtomicUsize::new(0),
+            #[cfg(test)]
+            timeouts,
         }
     }
 
-    pub fn offer(&self, item: T, t: Instant) {
-        self.op_sender
-            .as_ref()
-            .unwrap()
-            .send(Op::Offer(t, item))
-            .unwrap();
+    // dropping the handle leaves the item scheduled, the items offered after close are dropped
+    pub fn offer(&self, item: T, t: Instant) -> ScheduleHandle<T> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let op_sender = self.op_sender.as_ref().unwrap();
+        op_sender.send(Op::Offer(id, t, item)).unwrap();
+        ScheduleHandle {
+            op_sender: op_sender.clone(),
+            id,
+        }
     }
 
+    // waits until an item is due, None once the queue is closed and no item is due
     pub fn take(&self) -> Option<T> {
-        let (res_snd, res_rx) = channel::<Option<T>>();
-        self.op_sender
-            .as_ref()
-            .unwrap()
-            .send(Op::Take(res_snd))
-            .unwrap();
+        self.take_until(None).ok()
+    }
+
+    pub fn try_take(&self) -> Result<T, TryRecvError> {
+        self.take_until(Some(Instant::now())).map_err(|e| match e {
+            RecvTimeoutError::Timeout => TryRecvError::Empty,
+            RecvTimeoutError::Disconnected => TryRecvError::Disconnected,
+        })
+    }
+
+    pub fn take_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        // a timeout too long to be represented is no timeout at all
+        self.take_until(Instant::now().checked_add(timeout))
+    }
+
+    // when the next item is due, even if it is due already
+    pub fn peek_next_deadline(&self) -> Option<Instant> {
+        let (deadline_snd, deadline_rx) = channel();
+        self.request(Op::Peek(deadline_snd));
+        deadline_rx.recv().unwrap()
+    }
+
+    // wakes up the waiting takers, the items still in the queue can be taken once due
+    pub fn close(&self) {
+        self.request(Op::Close);
+    }
+
+    // the limit is enforced by the worker, so that an item is never sent to a taker that left
+    fn take_until(&self, limit: Option<Instant>) -> Taken<T> {
+        let (res_snd, res_rx) = channel::<Taken<T>>();
+        self.request(Op::Take(limit, res_snd));
         res_rx.recv().unwrap()
     }
 
+    fn request(&self, op: Op<T>) {
+        self.op_sender.as_ref().unwrap().send(op).unwrap();
+    }
+
     pub fn size(&self) -> usize {
         let (len_snd, len_rx) = channel::<usize>();
         self.op_sender
@@ -132,12 +214,137 @@ where
     }
 }
 
+impl<T> Default for DelayedQueue<T>
+where
+    T: Send + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// refers to an offered item, until it is taken
+pub struct ScheduleHandle<T: Send> {
+    op_sender: Sender<Op<T>>,
+    id: usize,
+}
+
+impl<T: Send> ScheduleHandle<T> {
+    // gives the item back, if it wasn't taken yet
+    pub fn cancel(&self) -> Option<T> {
+        let (item_snd, item_rx) = channel();
+        self.op_sender.send(Op::Cancel(self.id, item_snd)).ok()?;
+        item_rx.recv().ok().flatten()
+    }
+
+    // false if the item was taken or cancelled already
+    pub fn reschedule(&self, t: Instant) -> bool {
+        let (done_snd, done_rx) = channel();
+        if self
+            .op_sender
+            .send(Op::Reschedule(self.id, t, done_snd))
+            .is_err()
+        {
+            return false;
+        }
+        done_rx.recv().unwrap_or(false)
+    }
+}
+
 impl<T> Drop for DelayedQueue<T>
 where
     T: Send,
 {
     fn drop(&mut self) {
-        self.op_sender.take();
+        self.op_sender.take().unwrap().send(Op::Stop).unwrap();
         self.thread.take().unwrap().join().unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_cancelled_item_is_never_taken() {
+        let queue = DelayedQueue::new();
+        let now = Instant::now();
+        let first = queue.offer(1, now);
+        queue.offer(2, now + Duration::from_millis(10));
+        assert_eq!(first.cancel(), Some(1));
+        assert_eq!(first.cancel(), None);
+        assert_eq!(queue.size(), 1);
+        assert_eq!(queue.take(), Some(2));
+        assert_eq!(queue.try_take(), Err(TryRecvError::Empty));
+    }
+
+    #[test]
+    fn a_rescheduled_item_moves_in_the_queue() {
+        let queue = DelayedQueue::new();
+        let now = Instant::now();
+        let late = queue.offer("late", now + Duration::from_secs(60));
+        queue.offer("soon", now + Duration::from_millis(20));
+        assert!(late.reschedule(now));
+        assert_eq!(queue.size(), 2);
+        assert_eq!(queue.take(), Some("late"));
+        assert!(!late.reschedule(now));
+        assert_eq!(queue.take(), Some("soon"));
+    }
+
+    #[test]
+    fn handles_outliving_the_queue_do_nothing() {
+        let queue = DelayedQueue::new();
+        let handle = queue.offer(1, Instant::now());
+        drop(queue);
+        assert_eq!(handle.cancel(), None);
+        assert!(!handle.reschedule(Instant::now()));
+    }
+
+    #[test]
+    fn take_timeout_tells_a_timeout_from_a_closed_queue() {
+        let queue = std::sync::Arc::new(DelayedQueue::new());
+        let now = Instant::now();
+        queue.offer(1, now + Duration::from_millis(30));
+        assert_eq!(
+            queue.peek_next_deadline(),
+            Some(now + Duration::from_millis(30))
+        );
+        assert_eq!(
+            queue.take_timeout(Duration::from_millis(5)),
+            Err(RecvTimeoutError::Timeout)
+        );
+        assert_eq!(queue.take_timeout(Duration::MAX), Ok(1));
+        assert_eq!(queue.peek_next_deadline(), None);
+
+        let taker = spawn({
+            let queue = queue.clone();
+            move || queue.take()
+        });
+        std::thread::sleep(Duration::from_millis(20));
+        queue.close();
+        assert_eq!(taker.join().unwrap(), None);
+        assert_eq!(queue.try_take(), Err(TryRecvError::Disconnected));
+    }
+
+    #[test]
+    fn the_worker_survives_an_empty_queue() {
+        let queue = DelayedQueue::new();
+        assert_eq!(
+            queue.take_timeout(Duration::from_millis(5)),
+            Err(RecvTimeoutError::Timeout)
+        );
+        queue.offer(1, Instant::now());
+        assert_eq!(queue.take(), Some(1));
+    }
+
+    #[test]
+    fn the_worker_sleeps_with_a_due_item_and_no_taker() {
+        let queue = DelayedQueue::new();
+        queue.offer(1, Instant::now());
+        assert_eq!(queue.size(), 1);
+        std::thread::sleep(Duration::from_millis(50));
+        // a spinning worker would time out over and over again meanwhile
+        assert_eq!(queue.timeouts.load(Ordering::Relaxed), 0);
+        assert_eq!(queue.take(), Some(1));
+    }
+}
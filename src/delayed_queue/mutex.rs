@@ -1,96 +1,174 @@
 use rand::Rng;
-use std::cmp::Ordering;
-use std::fmt::Debug;
-use std::sync::{Arc, Condvar, Mutex};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::mpsc::{RecvTimeoutError, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex, Weak};
 use std::thread;
 use std::time::{Duration, Instant};
 
 const N_THREADS: usize = 24;
 
-#[derive(PartialEq, Clone, Copy, Debug)]
-enum TimeComparison {
-    Lesser,
-    Equal,
-    Greater,
+struct State<T> {
+    items: HashMap<usize, (Instant, T)>,
+    // one entry per offer or reschedule: the ones of cancelled or rescheduled items are stale,
+    // and get dropped when they reach the top, so that nothing has to be searched in the heap
+    deadlines: BinaryHeap<Reverse<(Instant, usize)>>,
+    next_id: usize,
+    closed: bool,
 }
 
-fn time_difference(i1: Instant, i2: Instant) -> (Duration, TimeComparison) {
-    return match i1.cmp(&i2) {
-        Ordering::Less => (i2.duration_since(i1), TimeComparison::Lesser),
-        Ordering::Equal => (i2.duration_since(i1), TimeComparison::Equal),
-        Ordering::Greater => (i1.duration_since(i2), TimeComparison::Greater),
-    };
+impl<T> State<T> {
+    // the live item due first, ties go to the one offered first
+    fn nearest(&mut self) -> Option<(Instant, usize)> {
+        while let Some(&Reverse((deadline, id))) = self.deadlines.peek() {
+            if self.items.get(&id).is_some_and(|(d, _)| *d == deadline) {
+                return Some((deadline, id));
+            }
+            self.deadlines.pop();
+        }
+        None
+    }
 }
 
-pub struct DelayedQueue<T: Send + Clone + Debug + PartialEq> {
-    queue: Mutex<Vec<(Instant, T)>>,
-    cv: Condvar,
+type Shared<T> = Arc<(Mutex<State<T>>, Condvar)>;
+
+pub struct DelayedQueue<T: Send> {
+    shared: Shared<T>,
 }
 
-impl<T: Send + Clone + Debug + PartialEq> DelayedQueue<T> {
+impl<T: Send> DelayedQueue<T> {
     pub fn new() -> Self {
         DelayedQueue {
-            queue: Mutex::new(Vec::new()),
-            cv: Condvar::new(),
+            shared: Arc::new((
+                Mutex::new(State {
+                    items: HashMap::new(),
+                    deadlines: BinaryHeap::new(),
+                    next_id: 0,
+                    closed: false,
+                }),
+                Condvar::new(),
+            )),
         }
     }
 
-    pub fn offer(&self, t: T, i: Instant) {
-        self.queue.lock().unwrap().push((i, t));
-        self.cv.notify_all();
+    // dropping the handle leaves the item scheduled, the items offered after close are dropped
+    pub fn offer(&self, t: T, i: Instant) -> ScheduleHandle<T> {
+        let mut lock = self.shared.0.lock().unwrap();
+        let id = lock.next_id;
+        lock.next_id += 1;
+        if !lock.closed {
+            lock.items.insert(id, (i, t));
+            lock.deadlines.push(Reverse((i, id)));
+            self.shared.1.notify_all();
+        }
+        ScheduleHandle {
+            queue: Arc::downgrade(&self.shared),
+            id,
+        }
     }
 
-    //the exercise is solved by supposing that the function extracts the seeked element from the queue
+    // waits until an item is due, None once the queue is closed and no item is due
     pub fn take(&self) -> Option<T> {
+        self.take_until(None).ok()
+    }
+
+    pub fn try_take(&self) -> Result<T, TryRecvError> {
+        self.take_until(Some(Instant::now())).map_err(|e| match e {
+            RecvTimeoutError::Timeout => TryRecvError::Empty,
+            RecvTimeoutError::Disconnected => TryRecvError::Disconnected,
+        })
+    }
+
+    pub fn take_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        // a timeout too long to be represented is no timeout at all
+        self.take_until(Instant::now().checked_add(timeout))
+    }
+
+    // when the next item is due, even if it is due already
+    pub fn peek_next_deadline(&self) -> Option<Instant> {
+        let mut lock = self.shared.0.lock().unwrap();
+        lock.nearest().map(|(deadline, _)| deadline)
+    }
+
+    // wakes up the waiting takers, the items still in the queue can be taken once due
+    pub fn close(&self) {
+        let mut lock = self.shared.0.lock().unwrap();
+        lock.closed = true;
+        self.shared.1.notify_all();
+    }
+
+    // waits forever without a limit
+    fn take_until(&self, limit: Option<Instant>) -> Result<T, RecvTimeoutError> {
+        let mut lock = self.shared.0.lock().unwrap();
         loop {
-            let mut lock = self.queue.lock().unwrap();
-            let queue_length = lock.len();
-            let mut nearest_difference: Option<(Duration, TimeComparison)> = None;
-            let mut nearest_element = None;
-            let mut nearest_pos = None;
+            let nearest = lock.nearest();
             let now = Instant::now();
-
-            if lock.len() == 0 {
-                break;
+            if let Some((deadline, id)) = nearest
+                && deadline <= now
+            {
+                lock.deadlines.pop();
+                let (_, item) = lock.items.remove(&id).unwrap();
+                self.shared.1.notify_all();
+                return Ok(item);
             }
-
-            let mut pos = 0;
-            for element in lock.iter() {
-                let current_difference = time_difference(element.0, now);
-                if nearest_element.is_none() || current_difference.0 < nearest_difference.unwrap().0
-                {
-                    nearest_difference = Some(current_difference);
-                    nearest_element = Some(element.clone());
-                    nearest_pos = Some(pos);
-                }
-                pos += 1;
+            if lock.closed {
+                return Err(RecvTimeoutError::Disconnected);
             }
-
-            if nearest_difference.unwrap().1 == TimeComparison::Greater {
-                lock = self
-                    .cv
-                    .wait_timeout_while(lock, nearest_difference.unwrap().0, |l| {
-                        (*l).len() == queue_length
-                    })
-                    .unwrap()
-                    .0;
-                if lock.len() == queue_length &&                                                               //  check if queue
-                    *(lock.iter().nth(nearest_pos.clone().unwrap()).unwrap()) == nearest_element.unwrap()
-                //  changed (in case, run again)
-                {
-                    return Some(lock.remove(nearest_pos.unwrap()).1);
-                }
-            } else {
-                let res = Some(lock.remove(nearest_pos.unwrap()).1);
-                self.cv.notify_all();
-                return res;
+            if limit.is_some_and(|limit| limit <= now) {
+                return Err(RecvTimeoutError::Timeout);
             }
-        } //loop
-        return None;
+            // any change to the queue wakes us up, and the nearest item is looked for again
+            let wake_up = match (nearest.map(|(deadline, _)| deadline), limit) {
+                (Some(deadline), Some(limit)) => Some(deadline.min(limit)),
+                (deadline, limit) => deadline.or(limit),
+            };
+            lock = match wake_up {
+                Some(wake_up) => self.shared.1.wait_timeout(lock, wake_up - now).unwrap().0,
+                None => self.shared.1.wait(lock).unwrap(),
+            };
+        }
     }
 
-    fn size(&self) -> usize {
-        self.queue.lock().unwrap().len()
+    pub fn size(&self) -> usize {
+        self.shared.0.lock().unwrap().items.len()
+    }
+}
+
+impl<T: Send> Default for DelayedQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// refers to an offered item, until it is taken
+pub struct ScheduleHandle<T: Send> {
+    queue: Weak<(Mutex<State<T>>, Condvar)>,
+    id: usize,
+}
+
+impl<T: Send> ScheduleHandle<T> {
+    // gives the item back, if it wasn't taken yet
+    pub fn cancel(&self) -> Option<T> {
+        let queue = self.queue.upgrade()?;
+        let mut lock = queue.0.lock().unwrap();
+        let (_, item) = lock.items.remove(&self.id)?;
+        queue.1.notify_all();
+        Some(item)
+    }
+
+    // false if the item was taken or cancelled already
+    pub fn reschedule(&self, i: Instant) -> bool {
+        let Some(queue) = self.queue.upgrade() else {
+            return false;
+        };
+        let mut lock = queue.0.lock().unwrap();
+        let Some((deadline, _)) = lock.items.get_mut(&self.id) else {
+            return false;
+        };
+        *deadline = i;
+        lock.deadlines.push(Reverse((i, self.id)));
+        queue.1.notify_all();
+        true
     }
 }
 
@@ -127,3 +205,73 @@ pub fn test() {
         h.join().unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_cancelled_item_is_never_taken() {
+        let queue = DelayedQueue::new();
+        let now = Instant::now();
+        let first = queue.offer(1, now);
+        queue.offer(2, now + Duration::from_millis(10));
+        assert_eq!(first.cancel(), Some(1));
+        assert_eq!(first.cancel(), None);
+        assert_eq!(queue.size(), 1);
+        assert_eq!(queue.take(), Some(2));
+        assert_eq!(queue.try_take(), Err(TryRecvError::Empty));
+    }
+
+    #[test]
+    fn a_rescheduled_item_moves_in_the_queue() {
+        let queue = DelayedQueue::new();
+        let now = Instant::now();
+        let late = queue.offer("late", now + Duration::from_secs(60));
+        queue.offer("soon", now + Duration::from_millis(20));
+        assert!(late.reschedule(now));
+        assert_eq!(queue.size(), 2);
+        assert_eq!(queue.take(), Some("late"));
+        assert!(!late.reschedule(now));
+        assert_eq!(queue.take(), Some("soon"));
+    }
+
+    #[test]
+    fn a_waiting_take_notices_the_changes() {
+        let queue = Arc::new(DelayedQueue::new());
+        let handle = queue.offer(1, Instant::now() + Duration::from_secs(60));
+        let taker = thread::spawn({
+            let queue = queue.clone();
+            move || queue.take()
+        });
+        thread::sleep(Duration::from_millis(20));
+        handle.reschedule(Instant::now());
+        assert_eq!(taker.join().unwrap(), Some(1));
+    }
+
+    #[test]
+    fn take_timeout_tells_a_timeout_from_a_closed_queue() {
+        let queue = Arc::new(DelayedQueue::new());
+        let now = Instant::now();
+        queue.offer(1, now + Duration::from_millis(30));
+        assert_eq!(
+            queue.peek_next_deadline(),
+            Some(now + Duration::from_millis(30))
+        );
+        assert_eq!(
+            queue.take_timeout(Duration::from_millis(5)),
+            Err(RecvTimeoutError::Timeout)
+        );
+        assert_eq!(queue.take_timeout(Duration::MAX), Ok(1));
+        assert_eq!(queue.peek_next_deadline(), None);
+
+        let taker = thread::spawn({
+            let queue = queue.clone();
+            move || queue.take()
+        });
+        thread::sleep(Duration::from_millis(20));
+        queue.close();
+        assert_eq!(taker.join().unwrap(), None);
+        assert_eq!(queue.try_take(), Err(TryRecvError::Disconnected));
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::VecDeque;
+use std::sync::mpsc::{RecvTimeoutError, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::spawn;
+use std::time::Duration;
+
+// every message is stored once and shared by all the subscriptions, which only keep the
+// sequence number of the next message to read: once the ring is full the oldest message is
+// overwritten and the subscribers that didn't read it in time count it as lag
+struct Ring<M> {
+    messages: VecDeque<Arc<M>>,
+    first_seq: usize, // sequence number of messages[0]
+    capacity: usize,
+    subscribers: usize,
+    connected: bool,
+}
+
+impl<M> Ring<M> {
+    fn next_seq(&self) -> usize {
+        self.first_seq + self.messages.len()
+    }
+}
+
+pub struct Dispatcher<M> {
+    ring: Arc<(Mutex<Ring<M>>, Condvar)>,
+}
+
+impl<M> Dispatcher<M> {
+    // a subscription may stay behind by at most `capacity` messages before losing some
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "The ring needs room for at least one message");
+        Self {
+            ring: Arc::new((
+                Mutex::new(Ring {
+                    messages: VecDeque::with_capacity(capacity),
+                    first_seq: 0,
+                    capacity,
+                    subscribers: 0,
+                    connected: true,
+                }),
+                Condvar::new(),
+            )),
+        }
+    }
+
+    pub fn dispatch(&self, message: M) {
+        let mut ring = self.ring.0.lock().unwrap();
+        if ring.subscribers == 0 {
+            return; // nobody could ever read it
+        }
+        if ring.messages.len() == ring.capacity {
+            ring.messages.pop_front();
+            ring.first_seq += 1;
+        }
+        ring.messages.push_back(Arc::new(message));
+        self.ring.1.notify_all();
+    }
+
+    // the subscription gets the messages dispatched from now on
+    pub fn subscribe(&self) -> Subscription<M> {
+        let mut ring = self.ring.0.lock().unwrap();
+        ring.subscribers += 1;
+        Subscription {
+            ring: self.ring.clone(),
+            cursor: Mutex::new(Cursor {
+                next: ring.next_seq(),
+                lag: 0,
+                end: None,
+            }),
+        }
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        self.ring.0.lock().unwrap().subscribers
+    }
+}
+
+impl<M> Drop for Dispatcher<M> {
+    fn drop(&mut self) {
+        let mut ring = self.ring.0.lock().unwrap();
+        ring.connected = false;
+        self.ring.1.notify_all();
+    }
+}
+
+struct Cursor {
+    next: usize,        // sequence number of the next message to read
+    lag: usize,         // messages overwritten before being read
+    end: Option<usize>, // set on unsubscribe, nothing after it will be read
+}
+
+// unregisters from the dispatcher when dropped
+pub struct Subscription<M> {
+    ring: Arc<(Mutex<Ring<M>>, Condvar)>,
+    cursor: Mutex<Cursor>,
+}
+
+impl<M> Subscription<M> {
+    pub fn read(&self) -> Option<Arc<M>> {
+        self.read_until(None).ok()
+    }
+
+    pub fn try_read(&self) -> Result<Arc<M>, TryRecvError> {
+        self.read_until(Some(Duration::ZERO)).map_err(|e| match e {
+            RecvTimeoutError::Timeout => TryRecvError::Empty,
+            RecvTimeoutError::Disconnected => TryRecvError::Disconnected,
+        })
+    }
+
+    pub fn read_timeout(&self, timeout: Duration) -> Result<Arc<M>, RecvTimeoutError> {
+        self.read_until(Some(timeout))
+    }
+
+    // number of messages overwritten before this subscription could read them
+    pub fn lag(&self) -> usize {
+        self.cursor.lock().unwrap().lag
+    }
+
+    // no more messages are delivered, the ones already dispatched can still be read
+    pub fn unsubscribe(&self) {
+        // always the ring first and then the cursor, a reader holds the ring while waiting
+        let mut ring = self.ring.0.lock().unwrap();
+        let mut cursor = self.cursor.lock().unwrap();
+        if cursor.end.is_none() {
+            ring.subscribers -= 1;
+            cursor.end = Some(ring.next_seq());
+            self.ring.1.notify_all(); // wakes up a reader of this subscription
+        }
+    }
+
+    // waits forever without a timeout
+    fn read_until(&self, timeout: Option<Duration>) -> Result<Arc<M>, RecvTimeoutError> {
+        let mut ring = self.ring.0.lock().unwrap();
+        let waiting = |r: &mut Ring<M>| {
+            let cursor = self.cursor.lock().unwrap();
+            r.connected && cursor.end.is_none() && cursor.next == r.next_seq()
+        };
+        ring = match timeout {
+            Some(timeout) => {
+                self.ring
+                    .1
+                    .wait_timeout_while(ring, timeout, waiting)
+                    .unwrap()
+                    .0
+            }
+            None => self.ring.1.wait_while(ring, waiting).unwrap(),
+        };
+        let mut cursor = self.cursor.lock().unwrap();
+        if cursor.next < ring.first_seq {
+            cursor.lag += ring.first_seq - cursor.next;
+            cursor.next = ring.first_seq;
+        }
+        let last = cursor.end.unwrap_or(ring.next_seq());
+        if cursor.next < last {
+            let message = ring.messages[cursor.next - ring.first_seq].clone();
+            cursor.next += 1;
+            return Ok(message);
+        }
+        if ring.connected && cursor.end.is_none() {
+            Err(RecvTimeoutError::Timeout)
+        } else {
+            Err(RecvTimeoutError::Disconnected)
+        }
+    }
+}
+
+impl<M> Drop for Subscription<M> {
+    fn drop(&mut self) {
+        self.unsubscribe();
+    }
+}
+
+pub fn test() {
+    let dispatcher = Dispatcher::<Vec<u8>>::new(16);
+    let handles = (0..3)
+        .map(|i| {
+            let subscription = dispatcher.subscribe();
+            spawn(move || {
+                while let Some(frame) = subscription.read() {
+                    println!("Reader {i} got a frame of {} bytes", frame.len());
+                }
+                println!("Reader {i} lost {} frames", subscription.lag());
+            })
+        })
+        .collect::<Vec<_>>();
+    (0..10).for_each(|n| dispatcher.dispatch(vec![0u8; 1 << n]));
+    drop(dispatcher);
+    handles
+        .into_iter()
+        .for_each(|handle| handle.join().unwrap());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_subscriber_shares_the_same_message() {
+        let dispatcher = Dispatcher::new(4);
+        let first = dispatcher.subscribe();
+        let second = dispatcher.subscribe();
+        dispatcher.dispatch(String::from("hello"));
+        drop(dispatcher);
+        let (a, b) = (first.read().unwrap(), second.read().unwrap());
+        assert!(Arc::ptr_eq(&a, &b));
+        assert_eq!(first.read(), None);
+    }
+
+    #[test]
+    fn a_slow_subscriber_skips_the_overwritten_messages() {
+        let dispatcher = Dispatcher::new(2);
+        let subscription = dispatcher.subscribe();
+        (0..5).for_each(|n| dispatcher.dispatch(n));
+        assert_eq!(subscription.try_read().as_deref(), Ok(&3));
+        assert_eq!(subscription.lag(), 3);
+        assert_eq!(subscription.try_read().as_deref(), Ok(&4));
+        assert_eq!(subscription.try_read(), Err(TryRecvError::Empty));
+    }
+
+    #[test]
+    fn unsubscribing_stops_the_delivery() {
+        let dispatcher = Dispatcher::new(4);
+        let subscription = dispatcher.subscribe();
+        let other = dispatcher.subscribe();
+        assert_eq!(dispatcher.subscriber_count(), 2);
+        dispatcher.dispatch(1);
+        subscription.unsubscribe();
+        drop(other);
+        assert_eq!(dispatcher.subscriber_count(), 0);
+        dispatcher.dispatch(2);
+        assert_eq!(subscription.read().as_deref(), Some(&1));
+        assert_eq!(subscription.read(), None);
+    }
+}
@@ -1,37 +1,88 @@
+use super::{Filter, Registry, Retention, TopicPattern};
 use rand::Rng;
-use std::sync::mpsc::{Receiver, Sender, channel};
-use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, TryRecvError, channel};
+use std::sync::{Arc, Mutex, Weak};
 use std::thread;
 use std::thread::sleep;
 use std::time::Duration;
 
+type Senders<Msg> = Mutex<Registry<Msg, Sender<Msg>>>;
+
 pub struct Dispatcher<Msg: Clone> {
-    senders_vec: Mutex<Vec<Sender<Msg>>>,
+    senders_vec: Arc<Senders<Msg>>,
 }
 
 impl<Msg: Clone> Dispatcher<Msg> {
     pub fn new() -> Dispatcher<Msg> {
+        Self::with_retention(Retention::Nothing)
+    }
+
+    //i messaggi conservati vengono rispediti a ogni nuova subscription interessata
+    pub fn with_retention(retention: Retention) -> Dispatcher<Msg> {
         Dispatcher {
-            senders_vec: Mutex::new(vec![]),
+            senders_vec: Arc::new(Mutex::new(Registry::new(retention))),
         }
     }
 
     pub fn subscribe(&self) -> Subscription<Msg> {
+        self.add_subscription(Filter::All)
+    }
+
+    // riceve solo i messaggi pubblicati con un topic che rispetta il pattern, es. `sensors.*.temp`
+    pub fn subscribe_topic(&self, pattern: &str) -> Subscription<Msg> {
+        self.add_subscription(Filter::Topic(TopicPattern::new(pattern)))
+    }
+
+    pub fn subscribe_filtered<F>(&self, predicate: F) -> Subscription<Msg>
+    where
+        F: Fn(&Msg) -> bool + Send + Sync + 'static,
+    {
+        self.add_subscription(Filter::Predicate(Box::new(predicate)))
+    }
+
+    fn add_subscription(&self, filter: Filter<Msg>) -> Subscription<Msg> {
         let (tx, rx) = channel();
         let mut lock = self.senders_vec.lock().unwrap();
-        (*lock).push(tx);
-        Subscription::new(rx)
+        for msg in lock.history.replay(&filter) {
+            tx.send(msg).unwrap(); //il receiver è ancora qui, non può fallire
+        }
+        let id = (*lock).add(filter, tx);
+        Subscription::new(rx, Arc::downgrade(&self.senders_vec), id)
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        self.senders_vec.lock().unwrap().len()
     }
 
     pub fn dispatch(&self, msg: Msg) {
-        let lock = self.senders_vec.lock().unwrap();
+        self.deliver(None, msg);
+    }
+
+    pub fn dispatch_topic(&self, topic: &str, msg: Msg) {
+        self.deliver(Some(topic), msg);
+    }
 
-        for sender in lock.iter() {
+    fn deliver(&self, topic: Option<&str>, msg: Msg) {
+        let mut lock = self.senders_vec.lock().unwrap();
+        lock.history.record(topic, &msg);
+
+        //il messaggio viene clonato solo per le subscription interessate
+        for (_, _, sender) in lock
+            .entries
+            .iter()
+            .filter(|(_, f, _)| f.accepts(topic, &msg))
+        {
             let _ = sender.send(msg.clone()); //ritorna errore se la subscription del receiver associato è stata droppata, quindi non fare unwrap altrimenti panica
         }
     }
 }
 
+impl<Msg: Clone> Default for Dispatcher<Msg> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 //aggiunto per ragioni di debug
 //la distruione del dispatcher implica la distruzione dei sender, che interrompe l'attesa sulle recv()
 impl<Msg: Clone> Drop for Dispatcher<Msg> {
@@ -40,16 +91,30 @@ impl<Msg: Clone> Drop for Dispatcher<Msg> {
     }
 }
 
+//la subscription tiene solo un riferimento debole al dispatcher, per potersi cancellare senza tenerlo in vita
 pub struct Subscription<Msg> {
     sub: Receiver<Msg>,
+    dispatcher: Weak<Senders<Msg>>,
+    id: usize,
 }
 
-impl<Msg: Clone> Subscription<Msg> {
-    fn new(rx: Receiver<Msg>) -> Self {
-        Subscription { sub: rx }
+impl<Msg> Subscription<Msg> {
+    fn new(rx: Receiver<Msg>, dispatcher: Weak<Senders<Msg>>, id: usize) -> Self {
+        Subscription {
+            sub: rx,
+            dispatcher,
+            id,
+        }
     }
 
-    fn read(&self) -> Option<Msg> {
+    //i messaggi già ricevuti possono ancora essere letti, dopodiché read() ritorna None
+    pub fn unsubscribe(&self) {
+        if let Some(dispatcher) = self.dispatcher.upgrade() {
+            dispatcher.lock().unwrap().remove(self.id);
+        }
+    }
+
+    pub fn read(&self) -> Option<Msg> {
         let msg = self.sub.recv();
         if msg.is_ok() {
             Some(msg.unwrap())
@@ -57,11 +122,20 @@ impl<Msg: Clone> Subscription<Msg> {
             None
         }
     }
+
+    pub fn try_read(&self) -> Result<Msg, TryRecvError> {
+        self.sub.try_recv()
+    }
+
+    pub fn read_timeout(&self, timeout: Duration) -> Result<Msg, RecvTimeoutError> {
+        self.sub.recv_timeout(timeout)
+    }
 }
 
-//aggiunto per ragioni di debug per mostrare che le subscription sono indipendenti l'una dall'altra
+//il print è per ragioni di debug, per mostrare che le subscription sono indipendenti l'una dall'altra
 impl<Msg> Drop for Subscription<Msg> {
     fn drop(&mut self) {
+        self.unsubscribe();
         println!(" '-> It's subscription is dropped!\n");
     }
 }
@@ -124,3 +198,89 @@ pub fn test() {
         h.join().unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subscribers_only_get_the_messages_they_are_interested_in() {
+        let dispatcher = Dispatcher::new();
+        let everything = dispatcher.subscribe();
+        let temperatures = dispatcher.subscribe_topic("sensors.*.temp");
+        let big = dispatcher.subscribe_filtered(|n: &usize| *n >= 10);
+        dispatcher.dispatch_topic("sensors.kitchen.temp", 21);
+        dispatcher.dispatch_topic("sensors.kitchen.humidity", 40);
+        dispatcher.dispatch(5);
+        drop(dispatcher);
+        assert_eq!(
+            std::iter::from_fn(|| everything.read()).collect::<Vec<_>>(),
+            vec![21, 40, 5]
+        );
+        assert_eq!(
+            std::iter::from_fn(|| temperatures.read()).collect::<Vec<_>>(),
+            vec![21]
+        );
+        assert_eq!(
+            std::iter::from_fn(|| big.read()).collect::<Vec<_>>(),
+            vec![21, 40]
+        );
+    }
+
+    #[test]
+    fn a_dropped_subscription_is_unregistered() {
+        let dispatcher = Dispatcher::new();
+        let first = dispatcher.subscribe();
+        let second = dispatcher.subscribe_topic("sensors.#");
+        assert_eq!(dispatcher.subscriber_count(), 2);
+        drop(second);
+        assert_eq!(dispatcher.subscriber_count(), 1);
+        dispatcher.dispatch(1);
+        first.unsubscribe();
+        dispatcher.dispatch(2);
+        assert_eq!(dispatcher.subscriber_count(), 0);
+        assert_eq!(first.read(), Some(1));
+        assert_eq!(first.read(), None);
+    }
+
+    #[test]
+    fn late_subscribers_get_the_retained_messages() {
+        let dispatcher = Dispatcher::with_retention(Retention::Last(2));
+        (0..5).for_each(|n| dispatcher.dispatch(n));
+        let late = dispatcher.subscribe_filtered(|n: &usize| n % 2 == 1);
+        dispatcher.dispatch(5);
+        drop(dispatcher);
+        assert_eq!(
+            std::iter::from_fn(|| late.read()).collect::<Vec<_>>(),
+            vec![3, 5]
+        );
+
+        let dispatcher = Dispatcher::with_retention(Retention::LastPerTopic);
+        dispatcher.dispatch_topic("sensors.kitchen.temp", 20);
+        dispatcher.dispatch_topic("sensors.garage.temp", 15);
+        dispatcher.dispatch_topic("sensors.kitchen.temp", 21);
+        dispatcher.dispatch_topic("sensors.kitchen.humidity", 40);
+        let dashboard = dispatcher.subscribe_topic("sensors.*.temp");
+        assert_eq!(dashboard.try_read(), Ok(15));
+        assert_eq!(dashboard.try_read(), Ok(21));
+        assert_eq!(dashboard.try_read(), Err(TryRecvError::Empty));
+    }
+
+    #[test]
+    fn read_timeout_tells_a_timeout_from_a_disconnection() {
+        let dispatcher = Dispatcher::<usize>::new();
+        let subscription = dispatcher.subscribe();
+        assert_eq!(
+            subscription.read_timeout(Duration::from_millis(10)),
+            Err(RecvTimeoutError::Timeout)
+        );
+        dispatcher.dispatch(1);
+        drop(dispatcher);
+        assert_eq!(subscription.read_timeout(Duration::from_millis(10)), Ok(1));
+        assert_eq!(
+            subscription.read_timeout(Duration::from_millis(10)),
+            Err(RecvTimeoutError::Disconnected)
+        );
+        assert_eq!(subscription.try_read(), Err(TryRecvError::Disconnected));
+    }
+}
@@ -1,3 +1,188 @@
+use std::collections::{HashMap, VecDeque};
+
+pub mod broadcast;
 pub mod hybrid;
 pub mod mpsc;
 pub mod mutex;
+#[cfg(unix)]
+pub mod socket;
+
+// dot separated segments, e.g. `sensors.*.temp`: a `*` matches exactly one segment of the topic,
+// a `#` matches whatever follows it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TopicPattern(Vec<Segment>);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Segment {
+    Word(String),
+    Any,
+    Rest,
+}
+
+impl TopicPattern {
+    pub fn new(pattern: &str) -> Self {
+        Self(
+            pattern
+                .split('.')
+                .map(|segment| match segment {
+                    "*" => Segment::Any,
+                    "#" => Segment::Rest,
+                    word => Segment::Word(word.to_string()),
+                })
+                .collect(),
+        )
+    }
+
+    pub fn matches(&self, topic: &str) -> bool {
+        let mut parts = topic.split('.');
+        for segment in &self.0 {
+            let matched = match segment {
+                Segment::Rest => return true,
+                Segment::Any => parts.next().is_some(),
+                Segment::Word(word) => parts.next() == Some(word.as_str()),
+            };
+            if !matched {
+                return false;
+            }
+        }
+        parts.next().is_none()
+    }
+}
+
+type Predicate<M> = Box<dyn Fn(&M) -> bool + Send + Sync>;
+
+// decides whether a subscriber gets a message, checked before the message is cloned for it
+enum Filter<M> {
+    All,
+    Topic(TopicPattern),
+    Predicate(Predicate<M>),
+}
+
+impl<M> Filter<M> {
+    // a message dispatched without topic never reaches the subscribers bound to a topic
+    fn accepts(&self, topic: Option<&str>, message: &M) -> bool {
+        match self {
+            Filter::All => true,
+            Filter::Topic(pattern) => topic.is_some_and(|topic| pattern.matches(topic)),
+            Filter::Predicate(predicate) => predicate(message),
+        }
+    }
+}
+
+// what a dispatcher keeps of the past messages, replayed to whoever subscribes later
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Retention {
+    Nothing,
+    Last(usize),  // the last n messages
+    LastPerTopic, // the last message of each topic, like a sticky value
+}
+
+struct History<M> {
+    retention: Retention,
+    recent: VecDeque<(Option<String>, M)>,
+    sticky: HashMap<Option<String>, (usize, M)>, // keyed by topic, with the arrival order
+    next_seq: usize,
+}
+
+impl<M> History<M> {
+    fn new(retention: Retention) -> Self {
+        Self {
+            retention,
+            recent: VecDeque::new(),
+            sticky: HashMap::new(),
+            next_seq: 0,
+        }
+    }
+}
+
+impl<M: Clone> History<M> {
+    fn record(&mut self, topic: Option<&str>, message: &M) {
+        match self.retention {
+            Retention::Nothing | Retention::Last(0) => {}
+            Retention::Last(n) => {
+                if self.recent.len() == n {
+                    self.recent.pop_front();
+                }
+                self.recent
+                    .push_back((topic.map(str::to_string), message.clone()));
+            }
+            Retention::LastPerTopic => {
+                let seq = self.next_seq;
+                self.next_seq += 1;
+                self.sticky
+                    .insert(topic.map(str::to_string), (seq, message.clone()));
+            }
+        }
+    }
+
+    // the retained messages accepted by `filter`, oldest first
+    fn replay(&self, filter: &Filter<M>) -> Vec<M> {
+        let mut retained = self
+            .recent
+            .iter()
+            .enumerate()
+            .map(|(seq, (topic, message))| (seq, topic, message))
+            .chain(
+                self.sticky
+                    .iter()
+                    .map(|(topic, (seq, message))| (*seq, topic, message)),
+            )
+            .filter(|(_, topic, message)| filter.accepts(topic.as_deref(), message))
+            .collect::<Vec<_>>();
+        retained.sort_by_key(|(seq, ..)| *seq);
+        retained
+            .into_iter()
+            .map(|(_, _, message)| message.clone())
+            .collect()
+    }
+}
+
+// the subscriptions known to a dispatcher, each handle keeps its id to unregister on drop
+struct Registry<M, S> {
+    next_id: usize,
+    entries: Vec<(usize, Filter<M>, S)>,
+    history: History<M>,
+}
+
+impl<M, S> Registry<M, S> {
+    fn new(retention: Retention) -> Self {
+        Self {
+            next_id: 0,
+            entries: Vec::new(),
+            history: History::new(retention),
+        }
+    }
+
+    fn add(&mut self, filter: Filter<M>, subscriber: S) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.entries.push((id, filter, subscriber));
+        id
+    }
+
+    fn remove(&mut self, id: usize) {
+        self.entries.retain(|(entry_id, ..)| *entry_id != id);
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards_match_whole_segments() {
+        let pattern = TopicPattern::new("sensors.*.temp");
+        assert!(pattern.matches("sensors.kitchen.temp"));
+        assert!(!pattern.matches("sensors.kitchen.humidity"));
+        assert!(!pattern.matches("sensors.temp"));
+        assert!(!pattern.matches("sensors.kitchen.oven.temp"));
+
+        let pattern = TopicPattern::new("sensors.#");
+        assert!(pattern.matches("sensors.kitchen.oven.temp"));
+        assert!(!pattern.matches("actuators.kitchen"));
+    }
+}
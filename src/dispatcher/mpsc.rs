@@ -1,16 +1,57 @@
-use std::sync::mpsc::{Receiver, Sender, channel};
+use super::{Filter, Registry, Retention, TopicPattern};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, TryRecvError, channel};
 use std::thread::{JoinHandle, spawn};
+use std::time::Duration;
 
-enum MessageOrSubscribe<M> {
-    Message(M),
-    Subscribe(Sender<M>),
+enum Request<M> {
+    Message(Option<String>, M),
+    Subscribe(Filter<M>, Sender<M>, Sender<usize>), // the id goes back on the last sender
+    Unsubscribe(usize),
+    Count(Sender<usize>),
+    Close,
 }
 
-pub struct Subscription<M>(Receiver<M>);
+// unregisters from the dispatcher when dropped
+pub struct Subscription<M> {
+    receiver: Receiver<M>,
+    requests: Sender<Request<M>>,
+    id: usize,
+}
 
 impl<M> Subscription<M> {
     pub fn read(&self) -> Option<M> {
-        self.0.recv().ok()
+        self.receiver.recv().ok()
+    }
+
+    pub fn try_read(&self) -> Result<M, TryRecvError> {
+        self.receiver.try_recv()
+    }
+
+    pub fn read_timeout(&self, timeout: Duration) -> Result<M, RecvTimeoutError> {
+        self.receiver.recv_timeout(timeout)
+    }
+
+    // no more messages are delivered, the ones already received can still be read
+    pub fn unsubscribe(&self) {
+        // the dispatcher might be gone already, there's nothing to unregister from then
+        let _ = self.requests.send(Request::Unsubscribe(self.id));
+    }
+
+    // unsubscribes from another thread, the receiver can't be shared
+    pub(super) fn unsubscriber(&self) -> impl FnOnce() + Send + use<M>
+    where
+        M: Send,
+    {
+        let (requests, id) = (self.requests.clone(), self.id);
+        move || {
+            let _ = requests.send(Request::Unsubscribe(id));
+        }
+    }
+}
+
+impl<M> Drop for Subscription<M> {
+    fn drop(&mut self) {
+        self.unsubscribe();
     }
 }
 
@@ -18,7 +59,7 @@ pub struct Dispatcher<M>
 where
     M: Clone,
 {
-    sender: Option<Sender<MessageOrSubscribe<M>>>,
+    sender: Option<Sender<Request<M>>>,
     thread: Option<JoinHandle<()>>,
 }
 
@@ -27,22 +68,39 @@ where
     M: Clone + Send + 'static,
 {
     pub fn new() -> Self {
-        let (snd, rx) = channel::<MessageOrSubscribe<M>>();
-        let mut subscriptions = Vec::<Sender<M>>::new();
+        Self::with_retention(Retention::Nothing)
+    }
+
+    // the retained messages are replayed to every new subscription accepting them
+    pub fn with_retention(retention: Retention) -> Self {
+        let (snd, rx) = channel::<Request<M>>();
+        let mut subscriptions = Registry::<M, Sender<M>>::new(retention);
         Self {
             sender: Some(snd),
             thread: Some(spawn(move || {
+                // the subscriptions hold senders too, the loop ends on Close
                 while let Ok(incoming) = rx.recv() {
                     match incoming {
-                        MessageOrSubscribe::Subscribe(s) => {
-                            subscriptions.push(s);
-                        }
-                        MessageOrSubscribe::Message(m) => {
-                            subscriptions = subscriptions
+                        Request::Subscribe(f, s, id_snd) => {
+                            subscriptions
+                                .history
+                                .replay(&f)
                                 .into_iter()
-                                .filter(|s| s.send(m.clone()).is_ok())
-                                .collect::<Vec<Sender<M>>>();
+                                .for_each(|m| s.send(m).unwrap());
+                            id_snd.send(subscriptions.add(f, s)).unwrap();
+                        }
+                        Request::Message(topic, m) => {
+                            subscriptions.history.record(topic.as_deref(), &m);
+                            // the message is cloned only for the subscriptions accepting it
+                            subscriptions.entries.retain(|(_, f, s)| {
+                                !f.accepts(topic.as_deref(), &m) || s.send(m.clone()).is_ok()
+                            });
+                        }
+                        Request::Unsubscribe(id) => subscriptions.remove(id),
+                        Request::Count(count_snd) => {
+                            count_snd.send(subscriptions.len()).unwrap();
                         }
+                        Request::Close => break,
                     }
                 }
             })),
@@ -50,21 +108,57 @@ where
     }
 
     pub fn dispatch(&self, msg: M) {
-        self.sender
-            .as_ref()
-            .unwrap()
-            .send(MessageOrSubscribe::Message(msg))
-            .unwrap();
+        self.request(Request::Message(None, msg));
+    }
+
+    pub fn dispatch_topic(&self, topic: &str, msg: M) {
+        self.request(Request::Message(Some(topic.to_string()), msg));
     }
 
     pub fn subscribe(&self) -> Subscription<M> {
+        self.add_subscription(Filter::All)
+    }
+
+    // only the messages dispatched with a topic matching `pattern`, e.g. `sensors.*.temp`
+    pub fn subscribe_topic(&self, pattern: &str) -> Subscription<M> {
+        self.add_subscription(Filter::Topic(TopicPattern::new(pattern)))
+    }
+
+    pub fn subscribe_filtered<F>(&self, predicate: F) -> Subscription<M>
+    where
+        F: Fn(&M) -> bool + Send + Sync + 'static,
+    {
+        self.add_subscription(Filter::Predicate(Box::new(predicate)))
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        let (count_snd, count_rx) = channel::<usize>();
+        self.request(Request::Count(count_snd));
+        count_rx.recv().unwrap()
+    }
+
+    fn add_subscription(&self, filter: Filter<M>) -> Subscription<M> {
         let (snd, rx) = channel::<M>();
-        self.sender
-            .as_ref()
-            .unwrap()
-            .send(MessageOrSubscribe::Subscribe(snd))
-            .unwrap();
-        Subscription(rx)
+        let (id_snd, id_rx) = channel::<usize>();
+        self.request(Request::Subscribe(filter, snd, id_snd));
+        Subscription {
+            receiver: rx,
+            requests: self.sender.as_ref().unwrap().clone(),
+            id: id_rx.recv().unwrap(),
+        }
+    }
+
+    fn request(&self, request: Request<M>) {
+        self.sender.as_ref().unwrap().send(request).unwrap();
+    }
+}
+
+impl<M> Default for Dispatcher<M>
+where
+    M: Clone + Send + 'static,
+{
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -73,7 +167,93 @@ where
     M: Clone,
 {
     fn drop(&mut self) {
-        self.sender.take().unwrap();
+        self.sender.take().unwrap().send(Request::Close).unwrap();
         self.thread.take().unwrap().join().unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subscribers_only_get_the_messages_they_are_interested_in() {
+        let dispatcher = Dispatcher::new();
+        let everything = dispatcher.subscribe();
+        let temperatures = dispatcher.subscribe_topic("sensors.*.temp");
+        let big = dispatcher.subscribe_filtered(|n: &usize| *n >= 10);
+        dispatcher.dispatch_topic("sensors.kitchen.temp", 21);
+        dispatcher.dispatch_topic("sensors.kitchen.humidity", 40);
+        dispatcher.dispatch(5);
+        drop(dispatcher);
+        assert_eq!(
+            std::iter::from_fn(|| everything.read()).collect::<Vec<_>>(),
+            vec![21, 40, 5]
+        );
+        assert_eq!(
+            std::iter::from_fn(|| temperatures.read()).collect::<Vec<_>>(),
+            vec![21]
+        );
+        assert_eq!(
+            std::iter::from_fn(|| big.read()).collect::<Vec<_>>(),
+            vec![21, 40]
+        );
+    }
+
+    #[test]
+    fn a_dropped_subscription_is_unregistered() {
+        let dispatcher = Dispatcher::new();
+        let first = dispatcher.subscribe();
+        let second = dispatcher.subscribe_topic("sensors.#");
+        assert_eq!(dispatcher.subscriber_count(), 2);
+        drop(second);
+        assert_eq!(dispatcher.subscriber_count(), 1);
+        dispatcher.dispatch(1);
+        first.unsubscribe();
+        dispatcher.dispatch(2);
+        assert_eq!(dispatcher.subscriber_count(), 0);
+        assert_eq!(first.read(), Some(1));
+        assert_eq!(first.read(), None);
+    }
+
+    #[test]
+    fn late_subscribers_get_the_retained_messages() {
+        let dispatcher = Dispatcher::with_retention(Retention::Last(2));
+        (0..5).for_each(|n| dispatcher.dispatch(n));
+        let late = dispatcher.subscribe_filtered(|n: &usize| n % 2 == 1);
+        dispatcher.dispatch(5);
+        drop(dispatcher);
+        assert_eq!(
+            std::iter::from_fn(|| late.read()).collect::<Vec<_>>(),
+            vec![3, 5]
+        );
+
+        let dispatcher = Dispatcher::with_retention(Retention::LastPerTopic);
+        dispatcher.dispatch_topic("sensors.kitchen.temp", 20);
+        dispatcher.dispatch_topic("sensors.garage.temp", 15);
+        dispatcher.dispatch_topic("sensors.kitchen.temp", 21);
+        dispatcher.dispatch_topic("sensors.kitchen.humidity", 40);
+        let dashboard = dispatcher.subscribe_topic("sensors.*.temp");
+        assert_eq!(dashboard.try_read(), Ok(15));
+        assert_eq!(dashboard.try_read(), Ok(21));
+        assert_eq!(dashboard.try_read(), Err(TryRecvError::Empty));
+    }
+
+    #[test]
+    fn read_timeout_tells_a_timeout_from_a_disconnection() {
+        let dispatcher = Dispatcher::<usize>::new();
+        let subscription = dispatcher.subscribe();
+        assert_eq!(
+            subscription.read_timeout(Duration::from_millis(10)),
+            Err(RecvTimeoutError::Timeout)
+        );
+        dispatcher.dispatch(1);
+        drop(dispatcher);
+        assert_eq!(subscription.read_timeout(Duration::from_millis(10)), Ok(1));
+        assert_eq!(
+            subscription.read_timeout(Duration::from_millis(10)),
+            Err(RecvTimeoutError::Disconnected)
+        );
+        assert_eq!(subscription.try_read(), Err(TryRecvError::Disconnected));
+    }
+}
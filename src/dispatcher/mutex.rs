@@ -1,5 +1,8 @@
+use super::{Filter, Registry, Retention, TopicPattern};
 use std::collections::VecDeque;
-use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc::{RecvTimeoutError, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::time::Duration;
 
 #[derive(PartialEq)]
 enum DispatcherState {
@@ -7,11 +10,22 @@ enum DispatcherState {
     Disconnected,
 }
 
+// what the dispatcher does with a message for a bounded subscription that is full
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overflow {
+    Block,      // waits for the subscriber to read, holding up every other subscription
+    DropOldest, // the oldest unread message makes room for the new one
+    DropNewest, // the new message is not delivered
+    Disconnect, // the subscription is dropped, its reader gets the buffered messages and then None
+}
+
+type Subscribers<M> = Mutex<Registry<M, Arc<Mailbox<M>>>>;
+
 pub struct Dispatcher<M>
 where
     M: Send + Clone,
 {
-    mutex: Mutex<Vec<Arc<Subscription<M>>>>,
+    mutex: Arc<Subscribers<M>>,
 }
 
 impl<M> Dispatcher<M>
@@ -19,25 +33,85 @@ where
     M: Send + Clone,
 {
     pub fn new() -> Self {
+        Self::with_retention(Retention::Nothing)
+    }
+
+    // the retained messages are replayed to every new subscription accepting them
+    pub fn with_retention(retention: Retention) -> Self {
         Self {
-            mutex: Mutex::new(Vec::<Arc<Subscription<M>>>::new()),
+            mutex: Arc::new(Mutex::new(Registry::new(retention))),
         }
     }
 
     pub fn dispatch(&self, message: M) {
-        let lock = self.mutex.lock().unwrap();
-        lock.iter()
-            .zip((0..(lock.len())).into_iter().map(|_| message.clone()))
-            .for_each(|(subscription_arc, m_clone)| {
-                subscription_arc.dispatch(m_clone);
-            });
+        self.deliver(None, message);
+    }
+
+    pub fn dispatch_topic(&self, topic: &str, message: M) {
+        self.deliver(Some(topic), message);
+    }
+
+    // the message is cloned only for the subscriptions accepting it
+    fn deliver(&self, topic: Option<&str>, message: M) {
+        let mut lock = self.mutex.lock().unwrap();
+        lock.history.record(topic, &message);
+        lock.entries.retain(|(_, filter, mailbox_arc)| {
+            !filter.accepts(topic, &message) || mailbox_arc.dispatch(message.clone())
+        });
     }
 
-    pub fn subscribe(&self) -> Arc<Subscription<M>> {
-        let sub = Arc::new(Subscription::new());
+    pub fn subscribe(&self) -> Subscription<M> {
+        self.add_subscription(Filter::All, None)
+    }
+
+    // only the messages dispatched with a topic matching `pattern`, e.g. `sensors.*.temp`
+    pub fn subscribe_topic(&self, pattern: &str) -> Subscription<M> {
+        self.add_subscription(Filter::Topic(TopicPattern::new(pattern)), None)
+    }
+
+    pub fn subscribe_filtered<F>(&self, predicate: F) -> Subscription<M>
+    where
+        F: Fn(&M) -> bool + Send + Sync + 'static,
+    {
+        self.add_subscription(Filter::Predicate(Box::new(predicate)), None)
+    }
+
+    // at most `capacity` unread messages are kept, `overflow` decides what happens to the others
+    pub fn subscribe_bounded(&self, capacity: usize, overflow: Overflow) -> Subscription<M> {
+        assert!(
+            capacity > 0,
+            "A bounded subscription needs room for at least one message"
+        );
+        self.add_subscription(Filter::All, Some((capacity, overflow)))
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        self.mutex.lock().unwrap().len()
+    }
+
+    fn add_subscription(
+        &self,
+        filter: Filter<M>,
+        bound: Option<(usize, Overflow)>,
+    ) -> Subscription<M> {
+        let mailbox = Arc::new(Mailbox::new(bound));
         let mut lock = self.mutex.lock().unwrap();
-        lock.push(sub.clone());
-        sub
+        mailbox.replay(lock.history.replay(&filter));
+        let id = lock.add(filter, mailbox.clone());
+        Subscription {
+            mailbox,
+            dispatcher: Arc::downgrade(&self.mutex),
+            id,
+        }
+    }
+}
+
+impl<M> Default for Dispatcher<M>
+where
+    M: Send + Clone,
+{
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -47,45 +121,154 @@ where
 {
     fn drop(&mut self) {
         let lock = self.mutex.lock().unwrap();
-        lock.iter()
-            .for_each(|subscription| subscription.disconnect());
+        lock.entries
+            .iter()
+            .for_each(|(_, _, mailbox)| mailbox.disconnect());
     }
 }
 
+// unregisters from the dispatcher when dropped
 pub struct Subscription<M>
 where
     M: Send + Clone,
 {
-    mutex: Mutex<(VecDeque<M>, DispatcherState)>,
-    condvar: Condvar,
+    mailbox: Arc<Mailbox<M>>,
+    dispatcher: Weak<Subscribers<M>>,
+    id: usize,
 }
 
 impl<M> Subscription<M>
 where
     M: Send + Clone,
 {
-    pub fn new() -> Self {
+    pub fn read(&self) -> Option<M> {
+        self.mailbox.read(None).ok()
+    }
+
+    pub fn try_read(&self) -> Result<M, TryRecvError> {
+        self.mailbox
+            .read(Some(Duration::ZERO))
+            .map_err(|e| match e {
+                RecvTimeoutError::Timeout => TryRecvError::Empty,
+                RecvTimeoutError::Disconnected => TryRecvError::Disconnected,
+            })
+    }
+
+    pub fn read_timeout(&self, timeout: Duration) -> Result<M, RecvTimeoutError> {
+        self.mailbox.read(Some(timeout))
+    }
+
+    // number of messages dropped or not delivered because the subscription was full
+    pub fn lag(&self) -> usize {
+        self.mailbox.mutex.lock().unwrap().2
+    }
+
+    // no more messages are delivered, the ones already received can still be read
+    pub fn unsubscribe(&self) {
+        // a dispatcher blocked on this subscription gives up before the registry is locked
+        self.mailbox.disconnect();
+        if let Some(dispatcher) = self.dispatcher.upgrade() {
+            dispatcher.lock().unwrap().remove(self.id);
+        }
+    }
+}
+
+impl<M> Drop for Subscription<M>
+where
+    M: Send + Clone,
+{
+    fn drop(&mut self) {
+        self.unsubscribe();
+    }
+}
+
+struct Mailbox<M> {
+    mutex: Mutex<(VecDeque<M>, DispatcherState, usize)>, // the count of missed messages last
+    condvar: Condvar,
+    bound: Option<(usize, Overflow)>,
+}
+
+impl<M> Mailbox<M> {
+    fn new(bound: Option<(usize, Overflow)>) -> Self {
         Self {
-            mutex: Mutex::new((VecDeque::<M>::new(), DispatcherState::Connected)),
+            mutex: Mutex::new((VecDeque::<M>::new(), DispatcherState::Connected, 0)),
             condvar: Condvar::new(),
+            bound,
         }
     }
 
-    pub fn read(&self) -> Option<M> {
+    // waits forever without a timeout
+    fn read(&self, timeout: Option<Duration>) -> Result<M, RecvTimeoutError> {
         let mut lock = self.mutex.lock().unwrap();
-        lock = self
-            .condvar
-            .wait_while(lock, |l| {
-                l.0.len() == 0 && l.1 == DispatcherState::Connected
-            })
-            .unwrap();
-        lock.0.pop_front()
+        let waiting = |l: &mut (VecDeque<M>, DispatcherState, usize)| {
+            l.0.len() == 0 && l.1 == DispatcherState::Connected
+        };
+        lock = match timeout {
+            Some(timeout) => {
+                self.condvar
+                    .wait_timeout_while(lock, timeout, waiting)
+                    .unwrap()
+                    .0
+            }
+            None => self.condvar.wait_while(lock, waiting).unwrap(),
+        };
+        let message = match lock.0.pop_front() {
+            Some(message) => message,
+            None if lock.1 == DispatcherState::Disconnected => {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            None => return Err(RecvTimeoutError::Timeout),
+        };
+        if self.bound.is_some() {
+            self.condvar.notify_all(); // a dispatcher might be waiting for room
+        }
+        Ok(message)
     }
 
-    fn dispatch(&self, message: M) {
+    // the history of a new subscription, of which a bounded one only keeps the most recent part
+    fn replay(&self, messages: Vec<M>) {
         let mut lock = self.mutex.lock().unwrap();
+        lock.0.extend(messages);
+        if let Some((capacity, _)) = self.bound {
+            let excess = lock.0.len().saturating_sub(capacity);
+            lock.0.drain(..excess);
+        }
+    }
+
+    // false once the subscription has been disconnected, for being too slow or by its reader
+    fn dispatch(&self, message: M) -> bool {
+        let mut lock = self.mutex.lock().unwrap();
+        if let Some((capacity, overflow)) = self.bound {
+            if overflow == Overflow::Block {
+                lock = self
+                    .condvar
+                    .wait_while(lock, |l| {
+                        l.0.len() >= capacity && l.1 == DispatcherState::Connected
+                    })
+                    .unwrap();
+            }
+            if lock.1 == DispatcherState::Disconnected {
+                return false;
+            }
+            if lock.0.len() >= capacity {
+                lock.2 += 1;
+                match overflow {
+                    Overflow::DropOldest => {
+                        lock.0.pop_front();
+                    }
+                    Overflow::DropNewest => return true,
+                    Overflow::Disconnect => {
+                        lock.1 = DispatcherState::Disconnected;
+                        self.condvar.notify_all();
+                        return false;
+                    }
+                    Overflow::Block => unreachable!(),
+                }
+            }
+        }
         lock.0.push_back(message);
-        self.condvar.notify_one();
+        self.condvar.notify_all();
+        true
     }
 
     fn disconnect(&self) {
@@ -94,3 +277,159 @@ where
         self.condvar.notify_all();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn subscribers_only_get_the_messages_they_are_interested_in() {
+        let dispatcher = Dispatcher::new();
+        let everything = dispatcher.subscribe();
+        let temperatures = dispatcher.subscribe_topic("sensors.*.temp");
+        let big = dispatcher.subscribe_filtered(|n: &usize| *n >= 10);
+        dispatcher.dispatch_topic("sensors.kitchen.temp", 21);
+        dispatcher.dispatch_topic("sensors.kitchen.humidity", 40);
+        dispatcher.dispatch(5);
+        drop(dispatcher);
+        assert_eq!(
+            std::iter::from_fn(|| everything.read()).collect::<Vec<_>>(),
+            vec![21, 40, 5]
+        );
+        assert_eq!(
+            std::iter::from_fn(|| temperatures.read()).collect::<Vec<_>>(),
+            vec![21]
+        );
+        assert_eq!(
+            std::iter::from_fn(|| big.read()).collect::<Vec<_>>(),
+            vec![21, 40]
+        );
+    }
+
+    #[derive(Debug)]
+    struct Counted(Arc<AtomicUsize>);
+
+    impl Clone for Counted {
+        fn clone(&self) -> Self {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Self(self.0.clone())
+        }
+    }
+
+    #[test]
+    fn messages_are_not_cloned_for_subscribers_not_interested() {
+        let clones = Arc::new(AtomicUsize::new(0));
+        let dispatcher = Dispatcher::new();
+        let _kitchen = dispatcher.subscribe_topic("sensors.kitchen.*");
+        let _garage = dispatcher.subscribe_topic("sensors.garage.*");
+        let _nothing = dispatcher.subscribe_filtered(|_: &Counted| false);
+        dispatcher.dispatch_topic("sensors.kitchen.temp", Counted(clones.clone()));
+        assert_eq!(clones.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn a_full_subscription_drops_messages_and_counts_them() {
+        let dispatcher = Dispatcher::new();
+        let oldest_dropped = dispatcher.subscribe_bounded(2, Overflow::DropOldest);
+        let newest_dropped = dispatcher.subscribe_bounded(2, Overflow::DropNewest);
+        (0..5).for_each(|n| dispatcher.dispatch(n));
+        drop(dispatcher);
+        assert_eq!(oldest_dropped.lag(), 3);
+        assert_eq!(
+            std::iter::from_fn(|| oldest_dropped.read()).collect::<Vec<_>>(),
+            vec![3, 4]
+        );
+        assert_eq!(newest_dropped.lag(), 3);
+        assert_eq!(
+            std::iter::from_fn(|| newest_dropped.read()).collect::<Vec<_>>(),
+            vec![0, 1]
+        );
+    }
+
+    #[test]
+    fn a_slow_subscriber_can_be_disconnected() {
+        let dispatcher = Dispatcher::new();
+        let slow = dispatcher.subscribe_bounded(1, Overflow::Disconnect);
+        let other = dispatcher.subscribe();
+        (0..3).for_each(|n| dispatcher.dispatch(n));
+        assert_eq!(slow.read(), Some(0));
+        assert_eq!(slow.read(), None);
+        assert_eq!(slow.lag(), 1);
+        assert_eq!(other.read(), Some(0));
+        assert_eq!(dispatcher.subscriber_count(), 1);
+    }
+
+    #[test]
+    fn a_full_subscription_can_hold_up_the_dispatcher() {
+        let dispatcher = Arc::new(Dispatcher::new());
+        let subscription = dispatcher.subscribe_bounded(1, Overflow::Block);
+        let dispatcher_clone = dispatcher.clone();
+        let handle = std::thread::spawn(move || (0..3).for_each(|n| dispatcher_clone.dispatch(n)));
+        assert_eq!(
+            (0..3)
+                .map(|_| subscription.read().unwrap())
+                .collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+        handle.join().unwrap();
+        assert_eq!(subscription.lag(), 0);
+    }
+
+    #[test]
+    fn a_dropped_subscription_is_unregistered() {
+        let dispatcher = Dispatcher::new();
+        let first = dispatcher.subscribe();
+        let second = dispatcher.subscribe_topic("sensors.#");
+        assert_eq!(dispatcher.subscriber_count(), 2);
+        drop(second);
+        assert_eq!(dispatcher.subscriber_count(), 1);
+        dispatcher.dispatch(1);
+        first.unsubscribe();
+        dispatcher.dispatch(2);
+        assert_eq!(dispatcher.subscriber_count(), 0);
+        assert_eq!(first.read(), Some(1));
+        assert_eq!(first.read(), None);
+    }
+
+    #[test]
+    fn late_subscribers_get_the_retained_messages() {
+        let dispatcher = Dispatcher::with_retention(Retention::Last(2));
+        (0..5).for_each(|n| dispatcher.dispatch(n));
+        let late = dispatcher.subscribe_filtered(|n: &usize| n % 2 == 1);
+        dispatcher.dispatch(5);
+        drop(dispatcher);
+        assert_eq!(
+            std::iter::from_fn(|| late.read()).collect::<Vec<_>>(),
+            vec![3, 5]
+        );
+
+        let dispatcher = Dispatcher::with_retention(Retention::LastPerTopic);
+        dispatcher.dispatch_topic("sensors.kitchen.temp", 20);
+        dispatcher.dispatch_topic("sensors.garage.temp", 15);
+        dispatcher.dispatch_topic("sensors.kitchen.temp", 21);
+        dispatcher.dispatch_topic("sensors.kitchen.humidity", 40);
+        let dashboard = dispatcher.subscribe_topic("sensors.*.temp");
+        assert_eq!(dashboard.try_read(), Ok(15));
+        assert_eq!(dashboard.try_read(), Ok(21));
+        assert_eq!(dashboard.try_read(), Err(TryRecvError::Empty));
+    }
+
+    #[test]
+    fn read_timeout_tells_a_timeout_from_a_disconnection() {
+        let dispatcher = Dispatcher::<usize>::new();
+        let subscription = dispatcher.subscribe();
+        assert_eq!(
+            subscription.read_timeout(Duration::from_millis(10)),
+            Err(RecvTimeoutError::Timeout)
+        );
+        dispatcher.dispatch(1);
+        drop(dispatcher);
+        assert_eq!(subscription.read_timeout(Duration::from_millis(10)), Ok(1));
+        assert_eq!(
+            subscription.read_timeout(Duration::from_millis(10)),
+            Err(RecvTimeoutError::Disconnected)
+        );
+        assert_eq!(subscription.try_read(), Err(TryRecvError::Disconnected));
+    }
+}
@@ -0,0 +1,468 @@
+use super::mpsc::{Dispatcher, Subscription};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{JoinHandle, sleep, spawn};
+use std::time::Duration;
+
+// turns the messages into bytes and back, the same codec has to be used on both sides
+pub trait Codec<M>: Send + Sync + 'static {
+    fn encode(&self, message: &M) -> Vec<u8>;
+    fn decode(&self, bytes: &[u8]) -> Result<M, String>;
+}
+
+// the length comes from the peer, it mustn't make the reader allocate whatever it says
+pub const MAX_FRAME_LEN: usize = 16 << 20;
+
+// every frame is a big endian u32 with the length of the payload, followed by the payload
+fn write_frame(mut stream: &UnixStream, payload: &[u8]) -> io::Result<()> {
+    if payload.len() > MAX_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "frame too long",
+        ));
+    }
+    stream.write_all(&(payload.len() as u32).to_be_bytes())?;
+    stream.write_all(payload)
+}
+
+fn read_frame(mut stream: &UnixStream) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too long"));
+    }
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(50);
+
+// the first frame of a connection tells what the client wants to do
+const PUBLISHER: u8 = b'P';
+const SUBSCRIBER: u8 = b'S';
+const TOPIC_SUBSCRIBER: u8 = b'T'; // followed by the pattern
+
+// a published frame starts with the length of its topic, u32::MAX if it has none
+fn encode_message(topic: Option<&str>, message: Vec<u8>) -> Vec<u8> {
+    let len = topic.map_or(u32::MAX, |topic| topic.len() as u32);
+    let topic = topic.unwrap_or_default();
+    [&len.to_be_bytes(), topic.as_bytes(), &message].concat()
+}
+
+fn decode_message(frame: &[u8]) -> Option<(Option<&str>, &[u8])> {
+    let (len, rest) = frame.split_first_chunk::<4>()?;
+    match u32::from_be_bytes(*len) {
+        u32::MAX => Some((None, rest)),
+        len => {
+            let (topic, message) = rest.split_at_checked(len as usize)?;
+            Some((Some(std::str::from_utf8(topic).ok()?), message))
+        }
+    }
+}
+
+// makes the messages of a local dispatcher available to other processes of the same host,
+// which connect to the socket at `path` through a RemoteDispatcher
+pub struct DispatcherServer<M: Clone> {
+    dispatcher: Arc<Dispatcher<M>>,
+    path: PathBuf,
+    connections: Arc<Mutex<HashMap<usize, UnixStream>>>, // shut down on drop
+    stopping: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl<M: Clone + Send + 'static> DispatcherServer<M> {
+    pub fn bind<C: Codec<M>>(path: impl AsRef<Path>, codec: C) -> io::Result<Self> {
+        let listener = UnixListener::bind(path.as_ref())?;
+        let dispatcher = Arc::new(Dispatcher::new());
+        let connections = Arc::new(Mutex::new(HashMap::<usize, UnixStream>::new()));
+        let stopping = Arc::new(AtomicBool::new(false));
+        let codec = Arc::new(codec);
+        let thread = spawn({
+            // the connections only keep a weak reference, dropping the server drops the dispatcher
+            let dispatcher = Arc::downgrade(&dispatcher);
+            let connections = connections.clone();
+            let stopping = stopping.clone();
+            move || {
+                for (id, stream) in listener.incoming().enumerate() {
+                    if stopping.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    // running out of descriptors lasts until some connection closes, no point retrying at once
+                    let Ok(stream) = stream else {
+                        sleep(ACCEPT_BACKOFF);
+                        continue;
+                    };
+                    if let Ok(clone) = stream.try_clone() {
+                        connections.lock().unwrap().insert(id, clone);
+                    }
+                    let (dispatcher, codec) = (dispatcher.clone(), codec.clone());
+                    let connections = connections.clone();
+                    spawn(move || {
+                        serve(stream, dispatcher, codec);
+                        connections.lock().unwrap().remove(&id);
+                    });
+                }
+            }
+        });
+        Ok(Self {
+            dispatcher,
+            path: path.as_ref().to_path_buf(),
+            connections,
+            stopping,
+            thread: Some(thread),
+        })
+    }
+
+    // the local side, its messages reach the remote subscriptions too
+    pub fn dispatcher(&self) -> &Dispatcher<M> {
+        &self.dispatcher
+    }
+}
+
+impl<M: Clone> Drop for DispatcherServer<M> {
+    fn drop(&mut self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        let _ = UnixStream::connect(&self.path); // wakes up the listener
+        self.thread.take().unwrap().join().unwrap();
+        let _ = std::fs::remove_file(&self.path);
+        self.connections
+            .lock()
+            .unwrap()
+            .drain()
+            .for_each(|(_, stream)| {
+                let _ = stream.shutdown(std::net::Shutdown::Both);
+            });
+    }
+}
+
+fn serve<M, C>(stream: UnixStream, dispatcher: Weak<Dispatcher<M>>, codec: Arc<C>)
+where
+    M: Clone + Send + 'static,
+    C: Codec<M>,
+{
+    let Ok(hello) = read_frame(&stream) else {
+        return;
+    };
+    let subscription = match (hello.split_first(), dispatcher.upgrade()) {
+        (Some((&PUBLISHER, _)), strong) => {
+            drop(strong); // a connected publisher doesn't keep the dispatcher alive
+            while let Ok(frame) = read_frame(&stream) {
+                let (Some((topic, bytes)), Some(dispatcher)) =
+                    (decode_message(&frame), dispatcher.upgrade())
+                else {
+                    break;
+                };
+                let Ok(message) = codec.decode(bytes) else {
+                    break;
+                };
+                match topic {
+                    Some(topic) => dispatcher.dispatch_topic(topic, message),
+                    None => dispatcher.dispatch(message),
+                }
+            }
+            return;
+        }
+        (Some((&SUBSCRIBER, _)), Some(dispatcher)) => dispatcher.subscribe(),
+        (Some((&TOPIC_SUBSCRIBER, pattern)), Some(dispatcher)) => {
+            match std::str::from_utf8(pattern) {
+                Ok(pattern) => dispatcher.subscribe_topic(pattern),
+                Err(_) => return,
+            }
+        }
+        _ => return,
+    };
+    // the client waits for this before using the subscription, so no message gets lost
+    if write_frame(&stream, &[]).is_err() {
+        return;
+    }
+    // the client never writes again, the end of the stream means it dropped its subscription
+    let watcher = spawn({
+        let (stream, unsubscribe) = (stream.try_clone(), subscription.unsubscriber());
+        move || {
+            if let Ok(mut stream) = stream {
+                let _ = stream.read(&mut [0u8; 1]);
+            }
+            unsubscribe();
+        }
+    });
+    forward(&stream, &subscription, codec.as_ref());
+    let _ = stream.shutdown(std::net::Shutdown::Both);
+    watcher.join().unwrap();
+}
+
+fn forward<M, C: Codec<M>>(stream: &UnixStream, subscription: &Subscription<M>, codec: &C) {
+    while let Some(message) = subscription.read() {
+        if write_frame(stream, &codec.encode(&message)).is_err() {
+            break;
+        }
+    }
+}
+
+// the client side of a DispatcherServer, which might live in another process
+pub struct RemoteDispatcher<M, C: Codec<M>> {
+    path: PathBuf,
+    publisher: Mutex<UnixStream>,
+    codec: Arc<C>,
+    _message: PhantomData<fn(M) -> M>,
+}
+
+impl<M, C: Codec<M>> RemoteDispatcher<M, C> {
+    pub fn connect(path: impl AsRef<Path>, codec: C) -> io::Result<Self> {
+        let publisher = UnixStream::connect(path.as_ref())?;
+        write_frame(&publisher, &[PUBLISHER])?;
+        Ok(Self {
+            path: path.as_ref().to_path_buf(),
+            publisher: Mutex::new(publisher),
+            codec: Arc::new(codec),
+            _message: PhantomData,
+        })
+    }
+
+    pub fn dispatch(&self, message: M) -> io::Result<()> {
+        self.publish(None, message)
+    }
+
+    pub fn dispatch_topic(&self, topic: &str, message: M) -> io::Result<()> {
+        self.publish(Some(topic), message)
+    }
+
+    fn publish(&self, topic: Option<&str>, message: M) -> io::Result<()> {
+        let frame = encode_message(topic, self.codec.encode(&message));
+        write_frame(&self.publisher.lock().unwrap(), &frame)
+    }
+
+    pub fn subscribe(&self) -> io::Result<RemoteSubscription<M, C>> {
+        self.open_subscription(vec![SUBSCRIBER])
+    }
+
+    // only the messages dispatched with a topic matching `pattern`, e.g. `sensors.*.temp`
+    pub fn subscribe_topic(&self, pattern: &str) -> io::Result<RemoteSubscription<M, C>> {
+        self.open_subscription([&[TOPIC_SUBSCRIBER], pattern.as_bytes()].concat())
+    }
+
+    // every subscription has a connection of its own, closed when the subscription is dropped
+    fn open_subscription(&self, hello: Vec<u8>) -> io::Result<RemoteSubscription<M, C>> {
+        let stream = UnixStream::connect(&self.path)?;
+        write_frame(&stream, &hello)?;
+        read_frame(&stream)?; // the server has registered the subscription
+        Ok(RemoteSubscription {
+            reader: Mutex::new(stream.try_clone()?),
+            stream,
+            codec: self.codec.clone(),
+            closed: AtomicBool::new(false),
+            _message: PhantomData,
+        })
+    }
+}
+
+pub struct RemoteSubscription<M, C: Codec<M>> {
+    // a frame is read in two steps, concurrent readers would take turns in the middle of one
+    reader: Mutex<UnixStream>,
+    stream: UnixStream, // shuts the connection down without waiting for the reader
+    codec: Arc<C>,
+    closed: AtomicBool, // the frames already received are dropped along with the stream
+    _message: PhantomData<fn() -> M>,
+}
+
+impl<M, C: Codec<M>> RemoteSubscription<M, C> {
+    // None once the server is gone. A frame that can't be read or decoded ends the subscription
+    // too: the stream is closed, so that the following reads don't pick up where it broke
+    pub fn read(&self) -> Option<M> {
+        let reader = self.reader.lock().unwrap();
+        if self.closed.load(Ordering::SeqCst) {
+            return None;
+        }
+        let message = read_frame(&reader)
+            .ok()
+            .and_then(|frame| self.codec.decode(&frame).ok());
+        if message.is_none() {
+            self.unsubscribe();
+        }
+        message
+    }
+
+    // no more messages are delivered
+    pub fn unsubscribe(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        let _ = self.stream.shutdown(std::net::Shutdown::Both);
+    }
+}
+
+impl<M, C: Codec<M>> Drop for RemoteSubscription<M, C> {
+    fn drop(&mut self) {
+        self.unsubscribe();
+    }
+}
+
+struct Text;
+
+impl Codec<String> for Text {
+    fn encode(&self, message: &String) -> Vec<u8> {
+        message.as_bytes().to_vec()
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<String, String> {
+        String::from_utf8(bytes.to_vec()).map_err(|e| e.to_string())
+    }
+}
+
+pub fn test() {
+    let path = std::env::temp_dir().join("pds-dispatcher.sock");
+    let _ = std::fs::remove_file(&path);
+    let server = DispatcherServer::bind(&path, Text).unwrap();
+    let remote = RemoteDispatcher::connect(&path, Text).unwrap();
+    let subscription = remote.subscribe_topic("news.#").unwrap();
+    let reader = spawn(move || {
+        while let Some(message) = subscription.read() {
+            println!("Remote subscriber got {message}");
+        }
+    });
+    server
+        .dispatcher()
+        .dispatch_topic("news.sport", "the local side won".to_string());
+    remote
+        .dispatch_topic("news.weather", "it rains remotely".to_string())
+        .unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    drop(server);
+    reader.join().unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Utf8;
+
+    impl Codec<String> for Utf8 {
+        fn encode(&self, message: &String) -> Vec<u8> {
+            message.as_bytes().to_vec()
+        }
+
+        fn decode(&self, bytes: &[u8]) -> Result<String, String> {
+            String::from_utf8(bytes.to_vec()).map_err(|e| e.to_string())
+        }
+    }
+
+    // the server notices a closed connection in its own time
+    fn wait_for_no_subscribers(dispatcher: &Dispatcher<String>) {
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while dispatcher.subscriber_count() > 0 {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "the subscription is still registered"
+            );
+            sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn socket_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("pds-{}-{name}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn remote_subscriptions_behave_like_local_ones() {
+        let path = socket_path("pubsub");
+        let server = DispatcherServer::bind(&path, Utf8).unwrap();
+        let local = server.dispatcher().subscribe();
+        let remote = RemoteDispatcher::connect(&path, Utf8).unwrap();
+        let everything = remote.subscribe().unwrap();
+        let temperatures = remote.subscribe_topic("sensors.*.temp").unwrap();
+
+        remote.dispatch("from afar".to_string()).unwrap();
+        assert_eq!(local.read().unwrap(), "from afar");
+        server
+            .dispatcher()
+            .dispatch_topic("sensors.kitchen.temp", "21".to_string());
+        assert_eq!(everything.read().unwrap(), "from afar");
+        assert_eq!(everything.read().unwrap(), "21");
+        assert_eq!(temperatures.read().unwrap(), "21");
+
+        drop(server);
+        assert_eq!(everything.read(), None);
+    }
+
+    #[test]
+    fn an_oversized_frame_is_refused() {
+        let (mut writer, reader) = UnixStream::pair().unwrap();
+        writer.write_all(&u32::MAX.to_be_bytes()).unwrap();
+        let error = read_frame(&reader).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let error = write_frame(&writer, &vec![0u8; MAX_FRAME_LEN + 1]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    // refuses the messages saying "bad"
+    struct Picky;
+
+    impl Codec<String> for Picky {
+        fn encode(&self, message: &String) -> Vec<u8> {
+            Utf8.encode(message)
+        }
+
+        fn decode(&self, bytes: &[u8]) -> Result<String, String> {
+            Utf8.decode(bytes)
+                .and_then(|message| match message.as_str() {
+                    "bad" => Err("bad message".to_string()),
+                    _ => Ok(message),
+                })
+        }
+    }
+
+    #[test]
+    fn an_undecodable_message_ends_the_subscription() {
+        let path = socket_path("undecodable");
+        let server = DispatcherServer::bind(&path, Utf8).unwrap();
+        let remote = RemoteDispatcher::connect(&path, Picky).unwrap();
+        let subscription = remote.subscribe().unwrap();
+        server.dispatcher().dispatch("bad".to_string());
+        server.dispatcher().dispatch("good".to_string());
+        assert_eq!(subscription.read(), None);
+        assert_eq!(subscription.read(), None);
+        wait_for_no_subscribers(server.dispatcher());
+    }
+
+    #[test]
+    fn concurrent_reads_keep_the_frames_whole() {
+        let path = socket_path("concurrent");
+        let server = DispatcherServer::bind(&path, Utf8).unwrap();
+        let remote = RemoteDispatcher::connect(&path, Utf8).unwrap();
+        let subscription = Arc::new(remote.subscribe().unwrap());
+        let sent = (0..4000).map(|i| i.to_string()).collect::<Vec<_>>();
+        sent.iter()
+            .for_each(|message| server.dispatcher().dispatch(message.clone()));
+        let readers = (0..8)
+            .map(|_| {
+                let subscription = subscription.clone();
+                spawn(move || (0..500).map(|_| subscription.read()).collect::<Vec<_>>())
+            })
+            .collect::<Vec<_>>();
+        let mut received = readers
+            .into_iter()
+            .flat_map(|reader| reader.join().unwrap())
+            .collect::<Option<Vec<_>>>()
+            .unwrap();
+        received.sort_by_key(|message| message.parse::<usize>().unwrap());
+        assert_eq!(received, sent);
+    }
+
+    #[test]
+    fn dropping_a_remote_subscription_unregisters_it() {
+        let path = socket_path("unsubscribe");
+        let server = DispatcherServer::bind(&path, Utf8).unwrap();
+        let remote = RemoteDispatcher::connect(&path, Utf8).unwrap();
+        let subscription = remote.subscribe().unwrap();
+        assert_eq!(server.dispatcher().subscriber_count(), 1);
+        drop(subscription);
+        wait_for_no_subscribers(server.dispatcher());
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::VecDeque;
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::sync::{Arc, Mutex};
+use std::thread::spawn;
+
+// values offered by the threads still waiting for a partner on the opposite side
+struct Slots<A, B> {
+    lefts: VecDeque<(A, Sender<B>)>,
+    rights: VecDeque<(B, Sender<A>)>,
+}
+
+// pairs `item` with the oldest waiter of the opposite side, or enqueues it returning where the answer will arrive
+fn pair<X, Y>(
+    own_side: &mut VecDeque<(X, Sender<Y>)>,
+    other_side: &mut VecDeque<(Y, Sender<X>)>,
+    item: X,
+) -> Result<Y, Receiver<Y>> {
+    match other_side.pop_front() {
+        Some((other_item, other_snd)) => {
+            other_snd.send(item).unwrap();
+            Ok(other_item)
+        }
+        None => {
+            let (snd, rx) = channel::<Y>();
+            own_side.push_back((item, snd));
+            Err(rx)
+        }
+    }
+}
+
+pub struct Exchanger<A, B>(Arc<Mutex<Slots<A, B>>>);
+
+impl<A, B> Exchanger<A, B> {
+    pub fn new() -> Self {
+        Self(Arc::new(Mutex::new(Slots {
+            lefts: VecDeque::new(),
+            rights: VecDeque::new(),
+        })))
+    }
+
+    pub fn left(&self) -> LeftHandle<A, B> {
+        LeftHandle(self.0.clone())
+    }
+
+    pub fn right(&self) -> RightHandle<A, B> {
+        RightHandle(self.0.clone())
+    }
+}
+
+impl<A, B> Default for Exchanger<A, B> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct LeftHandle<A, B>(Arc<Mutex<Slots<A, B>>>);
+
+impl<A, B> LeftHandle<A, B> {
+    // blocks until a thread on the right side exchanges its value
+    pub fn exchange(&self, item: A) -> B {
+        let mut guard = self.0.lock().unwrap();
+        let slots = &mut *guard;
+        let paired = pair(&mut slots.lefts, &mut slots.rights, item);
+        drop(guard);
+        paired.unwrap_or_else(|rx| rx.recv().unwrap())
+    }
+}
+
+impl<A, B> Clone for LeftHandle<A, B> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+pub struct RightHandle<A, B>(Arc<Mutex<Slots<A, B>>>);
+
+impl<A, B> RightHandle<A, B> {
+    // blocks until a thread on the left side exchanges its value
+    pub fn exchange(&self, item: B) -> A {
+        let mut guard = self.0.lock().unwrap();
+        let slots = &mut *guard;
+        let paired = pair(&mut slots.rights, &mut slots.lefts, item);
+        drop(guard);
+        paired.unwrap_or_else(|rx| rx.recv().unwrap())
+    }
+}
+
+impl<A, B> Clone for RightHandle<A, B> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+pub fn test() {
+    // double buffering: the filler hands over a full buffer and gets back an empty one
+    let exchanger = Exchanger::<Vec<usize>, Vec<usize>>::new();
+    let (filler, drainer) = (exchanger.left(), exchanger.right());
+    let filler_thread = spawn(move || {
+        let mut buffer = Vec::new();
+        for round in 0..3 {
+            buffer.extend((0..4).map(|n| round * 4 + n));
+            buffer = filler.exchange(buffer);
+            assert!(buffer.is_empty());
+        }
+    });
+    let mut buffer = Vec::new();
+    for _ in 0..3 {
+        buffer = drainer.exchange(buffer);
+        println!("Draining {:?}", buffer);
+        buffer.clear();
+    }
+    filler_thread.join().unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_side_receives_the_value_of_the_other() {
+        let exchanger = Exchanger::<usize, String>::new();
+        let (left, right) = (exchanger.left(), exchanger.right());
+        let handle = spawn(move || left.exchange(1));
+        assert_eq!(right.exchange("one".to_string()), 1);
+        assert_eq!(handle.join().unwrap(), "one");
+    }
+
+    #[test]
+    fn threads_on_the_same_side_are_never_paired() {
+        let exchanger = Exchanger::<usize, usize>::new();
+        let lefts = (0..5)
+            .map(|n| {
+                let left = exchanger.left();
+                spawn(move || left.exchange(n))
+            })
+            .collect::<Vec<_>>();
+        let rights = (100..105)
+            .map(|n| {
+                let right = exchanger.right();
+                spawn(move || right.exchange(n))
+            })
+            .collect::<Vec<_>>();
+        lefts
+            .into_iter()
+            .for_each(|h| assert!(h.join().unwrap() >= 100));
+        rights
+            .into_iter()
+            .for_each(|h| assert!(h.join().unwrap() < 100));
+    }
+}
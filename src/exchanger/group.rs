@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::spawn;
+
+pub struct GroupResult<T> {
+    pub generation: usize,
+    pub rank: usize, // arrival order inside the round, values[rank] is the caller's own value
+    pub values: Vec<T>,
+}
+
+struct State<T> {
+    generation: usize,
+    arrivals: Vec<T>,
+    // completed rounds are kept until every participant has read them, so a fast thread
+    // entering the next round can't overwrite the values of the previous one
+    completed: HashMap<usize, (Arc<Vec<T>>, usize)>,
+}
+
+pub struct ExchangeGroup<T: Clone> {
+    size: usize,
+    state: Mutex<State<T>>,
+    cv: Condvar,
+}
+
+impl<T: Clone> ExchangeGroup<T> {
+    pub fn new(size: usize) -> Result<Self, String> {
+        if size < 2 {
+            return Err("An exchange group needs at least 2 participants".to_string());
+        }
+        Ok(Self {
+            size,
+            state: Mutex::new(State {
+                generation: 0,
+                arrivals: Vec::with_capacity(size),
+                completed: HashMap::new(),
+            }),
+            cv: Condvar::new(),
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    // every participant receives the values of the whole round, in arrival order
+    pub fn exchange(&self, value: T) -> GroupResult<T> {
+        let (generation, rank, values) = self.join(value);
+        GroupResult {
+            generation,
+            rank,
+            values: Arc::unwrap_or_clone(values),
+        }
+    }
+
+    // every participant receives the value of the next one in arrival order, the last gets the first
+    pub fn exchange_rotated(&self, value: T) -> T {
+        let (_, rank, values) = self.join(value);
+        values[(rank + 1) % self.size].clone()
+    }
+
+    fn join(&self, value: T) -> (usize, usize, Arc<Vec<T>>) {
+        let mut state = self.state.lock().unwrap();
+        let generation = state.generation;
+        let rank = state.arrivals.len();
+        state.arrivals.push(value);
+        if state.arrivals.len() == self.size {
+            let values = std::mem::replace(&mut state.arrivals, Vec::with_capacity(self.size));
+            state
+                .completed
+                .insert(generation, (Arc::new(values), self.size));
+            state.generation += 1;
+            self.cv.notify_all();
+        } else {
+            state = self
+                .cv
+                .wait_while(state, |s| s.generation == generation)
+                .unwrap();
+        }
+        let (values, readers) = state.completed.get_mut(&generation).unwrap();
+        *readers -= 1;
+        let values = if *readers == 0 {
+            state.completed.remove(&generation).unwrap().0
+        } else {
+            values.clone()
+        };
+        (generation, rank, values)
+    }
+}
+
+pub fn test() {
+    let group = Arc::new(ExchangeGroup::<usize>::new(3).unwrap());
+    let handles = (0..6)
+        .map(|i| {
+            let group = group.clone();
+            spawn(move || {
+                let result = group.exchange(i);
+                println!(
+                    "Thread {i} got {:?} in generation {} with rank {}",
+                    result.values, result.generation, result.rank
+                );
+            })
+        })
+        .collect::<Vec<_>>();
+    handles
+        .into_iter()
+        .for_each(|handle| handle.join().unwrap());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_group_smaller_than_two_is_rejected() {
+        assert!(ExchangeGroup::<usize>::new(1).is_err());
+    }
+
+    #[test]
+    fn every_participant_receives_all_the_values() {
+        let group = ExchangeGroup::new(3).unwrap();
+        let results = std::thread::scope(|s| {
+            let handles = (0..3)
+                .map(|i| {
+                    let group = &group;
+                    s.spawn(move || group.exchange(i))
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        results.iter().for_each(|result| {
+            let mut values = result.values.clone();
+            values.sort();
+            assert_eq!(values, vec![0, 1, 2]);
+            assert_eq!(result.values, results[0].values);
+        });
+    }
+
+    #[test]
+    fn successive_rounds_do_not_mix() {
+        let group = ExchangeGroup::new(2).unwrap();
+        std::thread::scope(|s| {
+            (0..2).for_each(|t| {
+                let group = &group;
+                s.spawn(move || {
+                    (0..50).for_each(|round| {
+                        let result = group.exchange((round, t));
+                        assert_eq!(result.generation, round);
+                        assert!(result.values.iter().all(|(r, _)| *r == round));
+                    })
+                });
+            });
+        });
+    }
+
+    #[test]
+    fn rotated_exchange_assigns_the_next_arrival_value() {
+        let group = ExchangeGroup::new(3).unwrap();
+        let mut received = std::thread::scope(|s| {
+            let handles = (0..3)
+                .map(|i| {
+                    let group = &group;
+                    s.spawn(move || group.exchange_rotated(i))
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        received.sort();
+        assert_eq!(received, vec![0, 1, 2]);
+    }
+}
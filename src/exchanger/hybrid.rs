@@ -1,6 +1,6 @@
 use rand::Rng;
 use std::fmt::Debug;
-use std::sync::mpsc::{Receiver, Sender, channel};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, channel};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::thread::sleep;
@@ -8,46 +8,106 @@ use std::time::Duration;
 
 const N_THREADS: usize = 10;
 
-enum State {
-    First,
-    Second,
+// Ok with the partner's value, Err with the caller's own value when the exchange didn't happen
+pub(super) type ExchangeResult<T> = Result<T, T>;
+
+pub(super) enum Slot<T> {
+    Empty,
+    Waiting(usize, T, Sender<ExchangeResult<T>>),
+    Closed,
 }
 
-struct Exchanger<T: Debug> {
-    channel1: Mutex<(Sender<T>, Receiver<T>)>,
-    channel2: Mutex<(Sender<T>, Receiver<T>)>,
-    state: Mutex<State>,
+impl<T> Slot<T> {
+    // pairs with the waiter or takes its place, in that case the answer will arrive on the receiver
+    pub(super) fn offer(
+        &mut self,
+        id: usize,
+        value: T,
+    ) -> Result<ExchangeResult<T>, Receiver<ExchangeResult<T>>> {
+        match std::mem::replace(self, Slot::Empty) {
+            Slot::Closed => {
+                *self = Slot::Closed;
+                Ok(Err(value))
+            }
+            Slot::Waiting(_, first_value, first_snd) => {
+                first_snd.send(Ok(value)).unwrap();
+                Ok(Ok(first_value))
+            }
+            Slot::Empty => {
+                let (snd, rx) = channel::<ExchangeResult<T>>();
+                *self = Slot::Waiting(id, value, snd);
+                Err(rx)
+            }
+        }
+    }
+
+    // gives back the value of waiter `id` if it hasn't been paired yet
+    pub(super) fn withdraw(&mut self, id: usize) -> Option<T> {
+        match std::mem::replace(self, Slot::Empty) {
+            Slot::Waiting(waiting_id, value, _) if waiting_id == id => Some(value),
+            other => {
+                *self = other;
+                None
+            }
+        }
+    }
+
+    pub(super) fn close(&mut self) {
+        if let Slot::Waiting(_, value, snd) = std::mem::replace(self, Slot::Closed) {
+            snd.send(Err(value)).unwrap();
+        }
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        matches!(self, Slot::Empty)
+    }
+}
+
+// no lock is held while waiting for the partner: a waiter only owns a channel receiver,
+// so it can give up or be woken by close() without blocking the other threads
+pub struct Exchanger<T: Debug> {
+    slot: Mutex<(Slot<T>, usize)>,
 }
 
 impl<T: Debug> Exchanger<T> {
-    fn new() -> Arc<Self> {
-        let (s1, r1) = channel();
-        let (s2, r2) = channel();
-        return Arc::new(Exchanger {
-            channel1: Mutex::new((s1, r2)),
-            channel2: Mutex::new((s2, r1)),
-            state: Mutex::new(State::First),
-        });
+    pub fn new() -> Arc<Self> {
+        Arc::new(Exchanger {
+            slot: Mutex::new((Slot::Empty, 0)),
+        })
     }
 
-    fn exchange(&self, value: T) -> T {
-        let mut lock_state = self.state.lock().unwrap();
-        let lock;
+    pub fn exchange(&self, value: T) -> ExchangeResult<T> {
+        self.exchange_timeout(value, Duration::MAX)
+    }
 
-        match *lock_state {
-            State::First => {
-                lock = self.channel1.lock().unwrap();
-                *lock_state = State::Second;
-            }
-            State::Second => {
-                lock = self.channel2.lock().unwrap();
-                *lock_state = State::First;
+    // an unmatched waiter gives up after `timeout` and gets its value back
+    pub fn exchange_timeout(&self, value: T, timeout: Duration) -> ExchangeResult<T> {
+        let mut lock = self.slot.lock().unwrap();
+        let (slot, next_id) = &mut *lock;
+        let id = *next_id;
+        *next_id += 1;
+        let rx = match slot.offer(id, value) {
+            Ok(result) => return result,
+            Err(rx) => rx,
+        };
+        drop(lock);
+
+        match rx.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => {
+                if let Some(value) = self.slot.lock().unwrap().0.withdraw(id) {
+                    return Err(value);
+                }
+                // a partner (or close) got here first and has already answered
+                rx.recv().unwrap()
             }
+            Err(RecvTimeoutError::Disconnected) => unreachable!(),
         }
-        drop(lock_state);
+    }
 
-        (*lock).0.send(value).unwrap();
-        return (*lock).1.recv().unwrap();
+    // wakes up the unmatched waiter, if any, and rejects every following exchange
+    pub fn close(&self) {
+        self.slot.lock().unwrap().0.close();
     }
 }
 
@@ -64,8 +124,10 @@ pub fn test() {
                 let time = rand::rng().random_range(0..20);
                 sleep(Duration::from_secs(time));
                 println!("thread {} began exchanging procedure", i);
-                let v = e.exchange(i);
-                println!("> thread {} got value {}", i, v);
+                match e.exchange_timeout(i, Duration::from_secs(5)) {
+                    Ok(v) => println!("> thread {} got value {}", i, v),
+                    Err(v) => println!("> thread {} gave up, got back {}", i, v),
+                }
             }
         }))
     }
@@ -73,4 +135,44 @@ pub fn test() {
     for h in vec_join {
         h.join().unwrap();
     }
+    exchanger.close();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn two_threads_exchange_their_items() {
+        let exchanger = Exchanger::new();
+        let exchanger_clone = exchanger.clone();
+        let handle = thread::spawn(move || exchanger_clone.exchange(1));
+        assert_eq!(exchanger.exchange(2), Ok(1));
+        assert_eq!(handle.join().unwrap(), Ok(2));
+    }
+
+    #[test]
+    fn an_abandoned_waiter_gets_its_item_back_and_leaves_the_slot_free() {
+        let exchanger = Exchanger::new();
+        assert_eq!(
+            exchanger.exchange_timeout(1, Duration::from_millis(10)),
+            Err(1)
+        );
+        assert!(exchanger.slot.lock().unwrap().0.is_empty());
+        let exchanger_clone = exchanger.clone();
+        let handle = thread::spawn(move || exchanger_clone.exchange(2));
+        assert_eq!(exchanger.exchange(3), Ok(2));
+        assert_eq!(handle.join().unwrap(), Ok(3));
+    }
+
+    #[test]
+    fn close_wakes_up_the_unmatched_waiter() {
+        let exchanger = Exchanger::new();
+        let exchanger_clone = exchanger.clone();
+        let handle = thread::spawn(move || exchanger_clone.exchange(1));
+        sleep(Duration::from_millis(10));
+        exchanger.close();
+        assert_eq!(handle.join().unwrap(), Err(1));
+        assert_eq!(exchanger.exchange(2), Err(2));
+    }
 }
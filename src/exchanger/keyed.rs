@@ -0,0 +1,159 @@
+use super::hybrid::{ExchangeResult, Slot};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex};
+use std::thread::spawn;
+use std::time::Duration;
+
+struct State<K, T> {
+    slots: HashMap<K, Slot<T>>, // only keys with an unmatched waiter are kept
+    next_id: usize,
+    open: bool,
+}
+
+// many independent exchanges through the same object: threads are paired only with
+// partners presenting the same key, while different keys proceed concurrently
+pub struct KeyedExchanger<K, T> {
+    state: Mutex<State<K, T>>,
+}
+
+impl<K: Hash + Eq + Clone, T> KeyedExchanger<K, T> {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(State {
+                slots: HashMap::new(),
+                next_id: 0,
+                open: true,
+            }),
+        }
+    }
+
+    pub fn exchange_keyed(&self, key: K, value: T) -> ExchangeResult<T> {
+        self.exchange_keyed_timeout(key, value, Duration::MAX)
+    }
+
+    // an unmatched waiter gives up after `timeout` and gets its value back
+    pub fn exchange_keyed_timeout(&self, key: K, value: T, timeout: Duration) -> ExchangeResult<T> {
+        let mut state = self.state.lock().unwrap();
+        if !state.open {
+            return Err(value);
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        let slot = state.slots.entry(key.clone()).or_insert(Slot::Empty);
+        let rx = match slot.offer(id, value) {
+            Ok(result) => {
+                state.slots.remove(&key);
+                return result;
+            }
+            Err(rx) => rx,
+        };
+        drop(state);
+
+        match rx.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => {
+                let mut state = self.state.lock().unwrap();
+                if let Some(value) = state.slots.get_mut(&key).and_then(|s| s.withdraw(id)) {
+                    state.slots.remove(&key);
+                    return Err(value);
+                }
+                drop(state);
+                // a partner (or close) got here first and has already answered
+                rx.recv().unwrap()
+            }
+            Err(RecvTimeoutError::Disconnected) => unreachable!(),
+        }
+    }
+
+    // number of keys with a thread waiting for its partner
+    pub fn pending(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.slots.values().filter(|s| !s.is_empty()).count()
+    }
+
+    // wakes up every unmatched waiter and rejects the following exchanges
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.open = false;
+        state.slots.drain().for_each(|(_, mut slot)| slot.close());
+    }
+}
+
+impl<K: Hash + Eq + Clone, T> Default for KeyedExchanger<K, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn test() {
+    let exchanger = Arc::new(KeyedExchanger::<&str, usize>::new());
+    let handles = (0..6)
+        .map(|i| {
+            let exchanger = exchanger.clone();
+            let session = if i % 2 == 0 { "even" } else { "odd" };
+            spawn(move || {
+                let res = exchanger.exchange_keyed_timeout(session, i, Duration::from_secs(1));
+                println!("Thread {i} of session {session} got {:?}", res);
+            })
+        })
+        .collect::<Vec<_>>();
+    handles
+        .into_iter()
+        .for_each(|handle| handle.join().unwrap());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_threads_with_the_same_key_are_paired() {
+        let exchanger = Arc::new(KeyedExchanger::new());
+        let handles = [("a", 1), ("b", 2), ("a", 3), ("b", 4)]
+            .into_iter()
+            .map(|(key, value)| {
+                let exchanger = exchanger.clone();
+                spawn(move || (value, exchanger.exchange_keyed(key, value).unwrap()))
+            })
+            .collect::<Vec<_>>();
+        handles.into_iter().for_each(|handle| {
+            let (sent, received) = handle.join().unwrap();
+            assert_eq!(sent % 2, received % 2);
+            assert_ne!(sent, received);
+        });
+        assert_eq!(exchanger.pending(), 0);
+    }
+
+    #[test]
+    fn an_abandoned_waiter_leaves_no_slot_behind() {
+        let exchanger = KeyedExchanger::new();
+        assert_eq!(
+            exchanger.exchange_keyed_timeout(1, "alone", Duration::from_millis(10)),
+            Err("alone")
+        );
+        assert_eq!(exchanger.pending(), 0);
+        assert!(exchanger.state.lock().unwrap().slots.is_empty());
+    }
+
+    #[test]
+    fn close_wakes_up_every_waiter() {
+        let exchanger = Arc::new(KeyedExchanger::new());
+        let handles = (0..3)
+            .map(|key| {
+                let exchanger = exchanger.clone();
+                spawn(move || exchanger.exchange_keyed(key, key))
+            })
+            .collect::<Vec<_>>();
+        while exchanger.pending() < 3 {
+            std::thread::yield_now();
+        }
+        exchanger.close();
+        handles
+            .into_iter()
+            .enumerate()
+            .for_each(|(key, handle)| assert_eq!(handle.join().unwrap(), Err(key)));
+        assert_eq!(exchanger.exchange_keyed(0, 0), Err(0));
+    }
+}
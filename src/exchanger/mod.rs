@@ -1,3 +1,6 @@
+pub mod asymmetric;
+pub mod group;
 pub mod hybrid;
+pub mod keyed;
 pub mod mpsc;
 pub mod mutex;
@@ -1,41 +1,112 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{
     Arc,
-    mpsc::{Sender, channel},
+    mpsc::{RecvTimeoutError, Sender, channel},
 };
 use std::thread::{JoinHandle, spawn};
+use std::time::Duration;
+
+type ExchangeId = usize;
+// Ok with the partner's item, Err with the caller's own item when the exchange didn't happen
+type ExchangeResult<T> = Result<T, T>;
+
+enum Request<T> {
+    Exchange(ExchangeId, T, Sender<ExchangeResult<T>>),
+    Cancel(ExchangeId),
+    Close,
+}
 
 pub struct Exchanger<T: Send> {
-    sender: Option<Sender<(T, Sender<T>)>>,
+    sender: Option<Sender<Request<T>>>,
     thread: Option<JoinHandle<()>>,
+    next_id: AtomicUsize,
 }
 
 impl<T: Send + 'static> Exchanger<T> {
     pub fn new() -> Self {
-        let (sender, rx) = channel::<(T, Sender<T>)>();
+        let (sender, rx) = channel::<Request<T>>();
         let sender = Some(sender);
         Self {
             sender,
             thread: Some(spawn(move || {
-                let mut first = None as Option<(T, Sender<T>)>;
-                while let Ok((item, item_snd)) = rx.recv() {
-                    match first.take() {
-                        Some((first_item, first_snd)) => {
-                            first_snd.send(item).unwrap();
-                            item_snd.send(first_item).unwrap();
+                let mut first = None as Option<(ExchangeId, T, Sender<ExchangeResult<T>>)>;
+                let mut open = true;
+                while let Ok(request) = rx.recv() {
+                    match request {
+                        Request::Exchange(_, item, item_snd) if !open => {
+                            item_snd.send(Err(item)).unwrap();
+                        }
+                        Request::Exchange(id, item, item_snd) => match first.take() {
+                            Some((_, first_item, first_snd)) => {
+                                first_snd.send(Ok(item)).unwrap();
+                                item_snd.send(Ok(first_item)).unwrap();
+                            }
+                            None => {
+                                first = Some((id, item, item_snd));
+                            }
+                        },
+                        Request::Cancel(id) => {
+                            // the waiter might have been paired before its cancellation arrived
+                            if first.as_ref().is_some_and(|(first_id, ..)| *first_id == id) {
+                                let (_, item, item_snd) = first.take().unwrap();
+                                item_snd.send(Err(item)).unwrap();
+                            }
                         }
-                        None => {
-                            first = Some((item, item_snd));
+                        Request::Close => {
+                            open = false;
+                            if let Some((_, item, item_snd)) = first.take() {
+                                item_snd.send(Err(item)).unwrap();
+                            }
                         }
                     }
                 }
+                if let Some((_, item, item_snd)) = first.take() {
+                    let _ = item_snd.send(Err(item));
+                }
             })),
+            next_id: AtomicUsize::new(0),
         }
     }
-    pub fn exchange(&self, item: T) -> T {
-        let (snd, rx) = channel::<T>();
-        self.sender.as_ref().unwrap().send((item, snd)).unwrap();
+
+    pub fn exchange(&self, item: T) -> ExchangeResult<T> {
+        let (snd, rx) = channel::<ExchangeResult<T>>();
+        self.request(Request::Exchange(self.next_id(), item, snd));
         rx.recv().unwrap()
     }
+
+    // an unmatched waiter gives up after `timeout` and gets its item back
+    pub fn exchange_timeout(&self, item: T, timeout: Duration) -> ExchangeResult<T> {
+        let id = self.next_id();
+        let (snd, rx) = channel::<ExchangeResult<T>>();
+        self.request(Request::Exchange(id, item, snd));
+        match rx.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => {
+                self.request(Request::Cancel(id));
+                rx.recv().unwrap()
+            }
+            Err(RecvTimeoutError::Disconnected) => unreachable!(),
+        }
+    }
+
+    // wakes up the unmatched waiter, if any, and rejects every following exchange
+    pub fn close(&self) {
+        self.request(Request::Close);
+    }
+
+    fn next_id(&self) -> ExchangeId {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn request(&self, request: Request<T>) {
+        self.sender.as_ref().unwrap().send(request).unwrap();
+    }
+}
+
+impl<T: Send + 'static> Default for Exchanger<T> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<T: Send> Drop for Exchanger<T> {
@@ -50,8 +121,51 @@ pub fn test() {
     let e2 = e.clone();
 
     let _ = spawn(move || {
-        e2.exchange(10);
+        e2.exchange(10).unwrap();
     });
-    let res = e.exchange(1);
+    let res = e.exchange(1).unwrap();
     println!("{res}");
+
+    let res = e.exchange_timeout(2, Duration::from_millis(100));
+    println!("Nobody showed up, got back {:?}", res);
+    e.close();
+    println!("Exchange after close: {:?}", e.exchange(3));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn two_threads_exchange_their_items() {
+        let exchanger = Arc::new(Exchanger::new());
+        let exchanger_clone = exchanger.clone();
+        let handle = spawn(move || exchanger_clone.exchange(1));
+        assert_eq!(exchanger.exchange(2), Ok(1));
+        assert_eq!(handle.join().unwrap(), Ok(2));
+    }
+
+    #[test]
+    fn an_abandoned_waiter_gets_its_item_back_and_leaves_the_slot_free() {
+        let exchanger = Arc::new(Exchanger::new());
+        assert_eq!(
+            exchanger.exchange_timeout(1, Duration::from_millis(10)),
+            Err(1)
+        );
+        let exchanger_clone = exchanger.clone();
+        let handle = spawn(move || exchanger_clone.exchange(2));
+        assert_eq!(exchanger.exchange(3), Ok(2));
+        assert_eq!(handle.join().unwrap(), Ok(3));
+    }
+
+    #[test]
+    fn close_wakes_up_the_unmatched_waiter() {
+        let exchanger = Arc::new(Exchanger::new());
+        let exchanger_clone = exchanger.clone();
+        let handle = spawn(move || exchanger_clone.exchange(1));
+        std::thread::sleep(Duration::from_millis(10));
+        exchanger.close();
+        assert_eq!(handle.join().unwrap(), Err(1));
+        assert_eq!(exchanger.exchange(2), Err(2));
+    }
 }
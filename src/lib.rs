@@ -9,6 +9,9 @@ pub mod execution_limiter;
 pub mod joiner;
 pub mod looper;
 pub mod mpmc_channel;
+pub mod phaser;
+pub mod processor;
 pub mod ranking_barrier;
+pub mod single_thread_executor;
 pub mod synchronizer;
 pub mod token_manager;
@@ -1,2 +1,146 @@
+use std::any::Any;
+use std::cmp::Ordering;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::sync::mpsc::{Receiver, Sender};
+
 pub mod mpsc;
 pub mod mutex;
+pub mod pool;
+
+// higher values are more urgent, plain `send` uses the lowest priority
+pub type Priority = usize;
+
+// ordered by priority first, then by arrival: a BinaryHeap pops the oldest among the most urgent
+struct Prioritized<M> {
+    priority: Priority,
+    seq: usize,
+    message: M,
+}
+
+impl<M> PartialEq for Prioritized<M> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<M> Eq for Prioritized<M> {}
+
+impl<M> PartialOrd for Prioritized<M> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<M> Ord for Prioritized<M> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then(self.seq.cmp(&other.seq).reverse())
+    }
+}
+
+// what happens to the messages still queued when a looper is shut down
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Shutdown {
+    Drain,   // they are all processed, as it happens on drop
+    Discard, // they are given back to the owner, only the message being processed is completed
+}
+
+// a panic raised by `process` while handling a call is handed to the caller as its payload
+pub type ReplyResult<R> = Result<R, Box<dyn Any + Send + 'static>>;
+
+pub struct ReplyHandle<R>(Receiver<ReplyResult<R>>);
+
+impl<R> ReplyHandle<R> {
+    // blocks until the looper has processed the message
+    pub fn get(self) -> ReplyResult<R> {
+        // the thread drains the whole queue before exiting, unless its supervisor stopped it
+        self.0
+            .recv()
+            .unwrap_or_else(|_| Err(Box::new("the looper stopped before processing the message")))
+    }
+}
+
+// a message together with the channel of the caller waiting for its result, if any
+type Envelope<M, R> = (M, Option<Sender<ReplyResult<R>>>);
+
+fn reply_channel<R>() -> (Sender<ReplyResult<R>>, ReplyHandle<R>) {
+    let (snd, rx) = std::sync::mpsc::channel();
+    (snd, ReplyHandle(rx))
+}
+
+// what happens to the looper thread when `process` panics
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Supervision {
+    Stop,    // the following messages are discarded, then `cleanup` runs
+    Skip,    // the failed message is dropped and the same state goes on with the next one
+    Restart, // the failed message is dropped and the state is rebuilt by the factory
+}
+
+type Process<M, R> = Box<dyn FnMut(M) -> R + Send>;
+type FailureCallback = Box<dyn FnMut(&(dyn Any + Send)) + Send>;
+
+pub struct Supervisor<M, R> {
+    policy: Supervision,
+    factory: Box<dyn FnMut() -> Process<M, R> + Send>,
+    process: Process<M, R>,
+    on_failure: FailureCallback,
+}
+
+impl<M, R> Supervisor<M, R> {
+    // `factory` builds the processing state, once more after every failure if the policy is Restart
+    pub fn new<F, P>(policy: Supervision, mut factory: F) -> Self
+    where
+        F: FnMut() -> P + Send + 'static,
+        P: FnMut(M) -> R + Send + 'static,
+    {
+        let process: Process<M, R> = Box::new(factory());
+        Self {
+            policy,
+            factory: Box::new(move || Box::new(factory()) as Process<M, R>),
+            process,
+            on_failure: Box::new(|_| {}),
+        }
+    }
+
+    // invoked by the looper thread with the panic payload, before the policy is applied
+    pub fn on_failure<E>(mut self, on_failure: E) -> Self
+    where
+        E: FnMut(&(dyn Any + Send)) + Send + 'static,
+    {
+        self.on_failure = Box::new(on_failure);
+        self
+    }
+
+    fn skipping<P>(process: P) -> Self
+    where
+        P: FnMut(M) -> R + Send + 'static,
+    {
+        let mut process = Some(process);
+        Self::new(Supervision::Skip, move || process.take().unwrap())
+    }
+
+    // the caller of a failed call gets the panic payload, false if the thread has to stop
+    fn deliver(&mut self, (message, reply): Envelope<M, R>) -> bool {
+        let result = catch_unwind(AssertUnwindSafe(|| (self.process)(message)));
+        let keep_going = match &result {
+            Ok(_) => true,
+            Err(payload) => {
+                (self.on_failure)(payload.as_ref());
+                match self.policy {
+                    Supervision::Stop => false,
+                    Supervision::Skip => true,
+                    Supervision::Restart => {
+                        self.process = (self.factory)();
+                        true
+                    }
+                }
+            }
+        };
+        if let Some(reply) = reply {
+            // the handle might have been dropped, nobody is interested in the result then
+            let _ = reply.send(result);
+        }
+        keep_going
+    }
+}
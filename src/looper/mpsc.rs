@@ -1,39 +1,231 @@
-use std::sync::Arc;
-use std::sync::mpsc::{Sender, channel};
+use super::{Envelope, Prioritized, Priority, ReplyHandle, Shutdown, Supervisor, reply_channel};
+use std::collections::BinaryHeap;
+use std::sync::mpsc::{SendError, Sender, channel};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
 use std::thread::{JoinHandle, spawn};
+use std::time::{Duration, Instant};
 
-pub struct Looper<T: Send> {
-    sender: Option<Sender<T>>,
-    thread: Option<JoinHandle<()>>,
+type Queued<T, R> = (Priority, Envelope<T, R>);
+
+// the free places of a bounded looper: a sender takes one before sending, the thread gives it
+// back when it picks the message, so that the channel and the heap together never hold more
+// than `capacity` messages, like the queue of the mutex looper
+struct Room {
+    free: usize,
+    closed: bool, // set once the looper stops or shuts down, wakes up the blocked senders
+}
+
+type SharedRoom = Arc<(Mutex<Room>, Condvar)>;
+
+fn close(room: &Option<SharedRoom>) {
+    if let Some(room) = room {
+        room.0.lock().unwrap().closed = true;
+        room.1.notify_all();
+    }
 }
 
-impl<T: Send + 'static> Looper<T> {
-    pub fn new<P, C>(mut process: P, cleanup: C) -> Self
+pub struct Looper<T: Send, R: Send = ()> {
+    sender: Mutex<Option<Sender<Queued<T, R>>>>,
+    room: Option<SharedRoom>,
+    thread: Mutex<Option<JoinHandle<Vec<T>>>>,
+    deadline: Arc<OnceLock<Instant>>, // set on shutdown, the thread gives up draining once it's past
+}
+
+impl<T: Send + 'static, R: Send + 'static> Looper<T, R> {
+    pub fn new<P, C>(process: P, cleanup: C) -> Self
+    where
+        P: FnMut(T) -> R + Send + 'static,
+        C: FnOnce() + Send + 'static,
+    {
+        Self::build(None, Supervisor::skipping(process), cleanup)
+    }
+
+    // `send` blocks while `capacity` messages are waiting, `try_send` fails instead
+    pub fn with_capacity<P, C>(capacity: usize, process: P, cleanup: C) -> Self
+    where
+        P: FnMut(T) -> R + Send + 'static,
+        C: FnOnce() + Send + 'static,
+    {
+        assert!(
+            capacity > 0,
+            "A bounded looper needs room for at least one message"
+        );
+        Self::build(Some(capacity), Supervisor::skipping(process), cleanup)
+    }
+
+    // `cleanup` runs exactly once, whatever the supervisor decides about a failure
+    pub fn supervised<C>(capacity: Option<usize>, supervisor: Supervisor<T, R>, cleanup: C) -> Self
+    where
+        C: FnOnce() + Send + 'static,
+    {
+        assert!(
+            capacity != Some(0),
+            "A bounded looper needs room for at least one message"
+        );
+        Self::build(capacity, supervisor, cleanup)
+    }
+
+    fn build<C>(capacity: Option<usize>, mut supervisor: Supervisor<T, R>, cleanup: C) -> Self
     where
-        P: FnMut(T) -> () + Send + 'static,
-        C: FnOnce() -> () + Send + 'static,
+        C: FnOnce() + Send + 'static,
     {
-        let (sender, receiver) = channel::<T>();
+        let (sender, receiver) = channel::<Queued<T, R>>();
+        let room = capacity.map(|free| {
+            Arc::new((
+                Mutex::new(Room {
+                    free,
+                    closed: false,
+                }),
+                Condvar::new(),
+            ))
+        });
+        let room_clone = room.clone();
+        let deadline = Arc::new(OnceLock::<Instant>::new());
+        let deadline_clone = deadline.clone();
         let thread = spawn(move || {
-            while let Ok(message) = receiver.recv() {
-                process(message)
-            }
+            let mut buffer = BinaryHeap::<Prioritized<Envelope<T, R>>>::new();
+            let mut seq = 0;
+            let mut push = |buffer: &mut BinaryHeap<_>, (priority, message)| {
+                buffer.push(Prioritized {
+                    priority,
+                    seq,
+                    message,
+                });
+                seq += 1;
+            };
+            let discarded = loop {
+                if deadline_clone.get().is_some_and(|d| Instant::now() >= *d) {
+                    receiver
+                        .try_iter()
+                        .for_each(|incoming| push(&mut buffer, incoming));
+                    break std::iter::from_fn(|| buffer.pop())
+                        .map(|prioritized| prioritized.message.0)
+                        .collect();
+                }
+                if buffer.is_empty() {
+                    match receiver.recv() {
+                        Ok(incoming) => push(&mut buffer, incoming),
+                        Err(_) => break Vec::new(),
+                    }
+                }
+                // the messages already queued get a chance to skip ahead
+                receiver
+                    .try_iter()
+                    .for_each(|incoming| push(&mut buffer, incoming));
+                let next = buffer.pop().unwrap().message;
+                if let Some(room) = &room_clone {
+                    room.0.lock().unwrap().free += 1;
+                    room.1.notify_one();
+                }
+                if !supervisor.deliver(next) {
+                    break Vec::new();
+                }
+            };
+            // pending callers are told the looper stopped, the following sends are discarded
+            close(&room_clone);
+            drop(receiver);
+            drop(buffer);
             cleanup();
+            discarded
         });
-        let sender = Some(sender);
-        let thread = Some(thread);
-        Self { sender, thread }
+        Self {
+            sender: Mutex::new(Some(sender)),
+            room,
+            thread: Mutex::new(Some(thread)),
+            deadline,
+        }
     }
 
     pub fn send(&self, message: T) {
-        self.sender.as_ref().unwrap().send(message).unwrap();
+        self.send_with_priority(message, 0);
+    }
+
+    pub fn send_with_priority(&self, message: T, priority: Priority) {
+        self.enqueue((priority, (message, None)));
+    }
+
+    // the result of `process` is routed back through the handle
+    pub fn call(&self, message: T) -> ReplyHandle<R> {
+        self.call_with_priority(message, 0)
+    }
+
+    pub fn call_with_priority(&self, message: T, priority: Priority) -> ReplyHandle<R> {
+        let (reply, handle) = reply_channel();
+        self.enqueue((priority, (message, Some(reply))));
+        handle
+    }
+
+    fn enqueue(&self, queued: Queued<T, R>) {
+        if let Some(room) = &self.room {
+            let lock = room.0.lock().unwrap();
+            let mut lock = room
+                .1
+                .wait_while(lock, |r| r.free == 0 && !r.closed)
+                .unwrap();
+            if lock.closed {
+                return;
+            }
+            lock.free -= 1;
+        }
+        // once the looper has been stopped or shut down the message is discarded,
+        // a waiting caller finds out through its handle
+        let sender = self.sender.lock().unwrap().clone();
+        if let Some(sender) = sender {
+            let _ = sender.send(queued);
+        }
+    }
+
+    // the message is given back if the queue is full or the looper has been stopped or shut down
+    pub fn try_send(&self, message: T) -> Result<(), T> {
+        self.try_send_with_priority(message, 0)
+    }
+
+    pub fn try_send_with_priority(&self, message: T, priority: Priority) -> Result<(), T> {
+        if let Some(room) = &self.room {
+            let mut lock = room.0.lock().unwrap();
+            if lock.free == 0 || lock.closed {
+                return Err(message);
+            }
+            lock.free -= 1;
+        }
+        let sender = self.sender.lock().unwrap().clone();
+        match sender {
+            Some(sender) => sender
+                .send((priority, (message, None)))
+                .map_err(|SendError((_, (message, _)))| message),
+            None => Err(message),
+        }
     }
 }
 
-impl<T: Send> Drop for Looper<T> {
+impl<T: Send, R: Send> Looper<T, R> {
+    // the following messages are rejected, returns once the thread has run `cleanup`
+    pub fn shutdown(&self, mode: Shutdown) -> Vec<T> {
+        match mode {
+            Shutdown::Drain => self.shutdown_timeout(Duration::MAX),
+            Shutdown::Discard => self.shutdown_timeout(Duration::ZERO),
+        }
+    }
+
+    // drains the queue for at most `timeout`, the messages left are given back
+    // in the order they would have been processed
+    pub fn shutdown_timeout(&self, timeout: Duration) -> Vec<T> {
+        if let Some(deadline) = Instant::now().checked_add(timeout) {
+            let _ = self.deadline.set(deadline); // an earlier shutdown wins
+        }
+        // the deadline is set first: the thread wakes up on disconnection and finds it
+        close(&self.room);
+        self.sender.lock().unwrap().take();
+        match self.thread.lock().unwrap().take() {
+            Some(thread) => thread.join().unwrap(),
+            None => Vec::new(),
+        }
+    }
+}
+
+impl<T: Send, R: Send> Drop for Looper<T, R> {
     fn drop(&mut self) {
-        self.sender.take().unwrap();
-        self.thread.take().unwrap().join().unwrap();
+        self.shutdown(Shutdown::Drain);
     }
 }
 
@@ -60,3 +252,278 @@ pub fn test() {
         .into_iter()
         .for_each(|handle| handle.join().unwrap());
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::looper::Supervision;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn urgent_messages_skip_ahead_of_queued_ones() {
+        let (started_snd, started_rx) = channel::<()>();
+        let (gate_snd, gate_rx) = channel::<()>();
+        let processed = Arc::new(Mutex::new(Vec::new()));
+        let processed_clone = processed.clone();
+        let looper = Looper::new(
+            move |n: usize| {
+                if n == 0 {
+                    started_snd.send(()).unwrap();
+                    gate_rx.recv().unwrap();
+                }
+                processed_clone.lock().unwrap().push(n);
+            },
+            || {},
+        );
+        looper.send(0);
+        started_rx.recv().unwrap();
+        looper.send(1);
+        looper.send_with_priority(2, 5);
+        looper.send(3);
+        gate_snd.send(()).unwrap();
+        drop(looper);
+        assert_eq!(*processed.lock().unwrap(), vec![0, 2, 1, 3]);
+    }
+
+    #[test]
+    fn try_send_fails_when_the_queue_is_full() {
+        let (started_snd, started_rx) = channel::<()>();
+        let (gate_snd, gate_rx) = channel::<()>();
+        let looper = Looper::with_capacity(
+            1,
+            move |n: usize| {
+                if n == 0 {
+                    started_snd.send(()).unwrap();
+                    gate_rx.recv().unwrap();
+                }
+            },
+            || {},
+        );
+        looper.send(0);
+        started_rx.recv().unwrap();
+        assert_eq!(looper.try_send(1), Ok(()));
+        assert_eq!(looper.try_send(2), Err(2));
+        gate_snd.send(()).unwrap();
+    }
+
+    #[test]
+    fn send_blocks_at_exactly_the_capacity() {
+        let (started_snd, started_rx) = channel::<()>();
+        let (gate_snd, gate_rx) = channel::<()>();
+        let looper = Arc::new(Looper::with_capacity(
+            3,
+            move |n: usize| {
+                if n == 0 {
+                    started_snd.send(()).unwrap();
+                    gate_rx.recv().unwrap();
+                }
+            },
+            || {},
+        ));
+        looper.send(0);
+        started_rx.recv().unwrap();
+        // the message being processed doesn't count, three more fit
+        (1..=3).for_each(|n| looper.send(n));
+        let blocked = spawn({
+            let looper = looper.clone();
+            move || looper.send(4)
+        });
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!blocked.is_finished());
+        assert_eq!(looper.try_send(5), Err(5));
+        gate_snd.send(()).unwrap();
+        blocked.join().unwrap();
+    }
+
+    #[test]
+    fn a_blocked_send_does_not_hold_up_try_send() {
+        let (started_snd, started_rx) = channel::<()>();
+        let (gate_snd, gate_rx) = channel::<()>();
+        let looper = Arc::new(Looper::with_capacity(
+            1,
+            move |n: usize| {
+                if n == 0 {
+                    started_snd.send(()).unwrap();
+                    gate_rx.recv().unwrap();
+                }
+            },
+            || {},
+        ));
+        looper.send(0);
+        started_rx.recv().unwrap();
+        looper.send(1);
+        let blocked = spawn({
+            let looper = looper.clone();
+            move || looper.send(2)
+        });
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!blocked.is_finished());
+        let start = Instant::now();
+        assert_eq!(looper.try_send(3), Err(3));
+        assert!(start.elapsed() < Duration::from_millis(100));
+        gate_snd.send(()).unwrap();
+        blocked.join().unwrap();
+    }
+
+    #[test]
+    fn call_returns_the_result_of_process() {
+        let looper = Looper::new(|n: usize| n * 2, || {});
+        let handles = (0..5).map(|n| looper.call(n)).collect::<Vec<_>>();
+        let results = handles
+            .into_iter()
+            .map(|handle| handle.get().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(results, vec![0, 2, 4, 6, 8]);
+    }
+
+    #[test]
+    fn a_panicking_call_reports_to_its_caller_and_the_looper_goes_on() {
+        let looper = Looper::new(
+            |n: usize| {
+                if n == 0 {
+                    panic!("cannot process zero");
+                }
+                10 / n
+            },
+            || {},
+        );
+        let payload = looper.call(0).get().unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"cannot process zero"));
+        assert_eq!(looper.call(5).get().unwrap(), 2);
+    }
+
+    #[test]
+    fn a_panicking_send_does_not_kill_the_looper() {
+        let looper = Looper::new(
+            |n: usize| {
+                if n == 0 {
+                    panic!("cannot process zero");
+                }
+                n
+            },
+            || {},
+        );
+        looper.send(0);
+        assert_eq!(looper.call(1).get().unwrap(), 1);
+    }
+
+    #[test]
+    fn restart_rebuilds_the_state_and_reports_the_failure() {
+        let failures = Arc::new(Mutex::new(Vec::new()));
+        let failures_clone = failures.clone();
+        let supervisor = Supervisor::new(Supervision::Restart, || {
+            let mut count = 0;
+            move |n: usize| {
+                if n == 0 {
+                    panic!("cannot process zero");
+                }
+                count += 1;
+                count
+            }
+        })
+        .on_failure(move |payload| {
+            let message = payload.downcast_ref::<&str>().unwrap().to_string();
+            failures_clone.lock().unwrap().push(message);
+        });
+        let looper = Looper::supervised(None, supervisor, || {});
+        assert_eq!(looper.call(1).get().unwrap(), 1);
+        assert_eq!(looper.call(1).get().unwrap(), 2);
+        assert!(looper.call(0).get().is_err());
+        assert_eq!(looper.call(1).get().unwrap(), 1);
+        assert_eq!(*failures.lock().unwrap(), vec!["cannot process zero"]);
+    }
+
+    #[test]
+    fn stop_discards_the_following_messages_and_cleans_up_once() {
+        let cleanups = Arc::new(AtomicUsize::new(0));
+        let cleanups_clone = cleanups.clone();
+        let supervisor = Supervisor::new(Supervision::Stop, || {
+            |n: usize| {
+                if n == 0 {
+                    panic!("cannot process zero");
+                }
+            }
+        });
+        let looper = Looper::supervised(Some(1), supervisor, move || {
+            cleanups_clone.fetch_add(1, Ordering::SeqCst);
+        });
+        looper.send(0);
+        assert!(looper.call(1).get().is_err());
+        assert_eq!(looper.try_send(2), Err(2));
+        looper.send(3);
+        drop(looper);
+        assert_eq!(cleanups.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn discard_gives_back_the_queued_messages_in_processing_order() {
+        let (started_snd, started_rx) = channel::<()>();
+        let (gate_snd, gate_rx) = channel::<()>();
+        let looper = Arc::new(Looper::new(
+            move |n: usize| {
+                if n == 0 {
+                    started_snd.send(()).unwrap();
+                    gate_rx.recv().unwrap();
+                }
+            },
+            || {},
+        ));
+        looper.send(0);
+        started_rx.recv().unwrap();
+        looper.send(1);
+        looper.send_with_priority(2, 5);
+        looper.send(3);
+        let opener = spawn({
+            let looper = looper.clone();
+            move || {
+                // the message 0 ends only once shutdown has set the deadline, the others are past it
+                while looper.deadline.get().is_none() {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                gate_snd.send(()).unwrap();
+            }
+        });
+        assert_eq!(looper.shutdown(Shutdown::Discard), vec![2, 1, 3]);
+        opener.join().unwrap();
+    }
+
+    #[test]
+    fn drain_processes_everything_and_rejects_what_comes_later() {
+        let processed = Arc::new(AtomicUsize::new(0));
+        let cleanups = Arc::new(AtomicUsize::new(0));
+        let (processed_clone, cleanups_clone) = (processed.clone(), cleanups.clone());
+        let looper = Looper::new(
+            move |_: usize| {
+                processed_clone.fetch_add(1, Ordering::SeqCst);
+            },
+            move || {
+                cleanups_clone.fetch_add(1, Ordering::SeqCst);
+            },
+        );
+        (0..5).for_each(|n| looper.send(n));
+        assert!(looper.shutdown(Shutdown::Drain).is_empty());
+        assert_eq!(processed.load(Ordering::SeqCst), 5);
+        assert_eq!(looper.try_send(5), Err(5));
+        assert!(looper.call(6).get().is_err());
+        assert!(looper.shutdown(Shutdown::Discard).is_empty());
+        drop(looper);
+        assert_eq!(cleanups.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn shutdown_timeout_gives_back_what_could_not_be_processed_in_time() {
+        let processed = Arc::new(AtomicUsize::new(0));
+        let processed_clone = processed.clone();
+        let looper = Looper::new(
+            move |_: usize| {
+                std::thread::sleep(Duration::from_millis(20));
+                processed_clone.fetch_add(1, Ordering::SeqCst);
+            },
+            || {},
+        );
+        (0..10).for_each(|n| looper.send(n));
+        let discarded = looper.shutdown_timeout(Duration::from_millis(50));
+        assert!(!discarded.is_empty());
+        assert_eq!(processed.load(Ordering::SeqCst) + discarded.len(), 10);
+    }
+}
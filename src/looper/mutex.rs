@@ -1,70 +1,220 @@
-use std::collections::VecDeque;
+use super::{Envelope, Prioritized, Priority, ReplyHandle, Shutdown, Supervisor, reply_channel};
+use std::collections::BinaryHeap;
 use std::sync::{Arc, Condvar, Mutex};
 use std::thread::{JoinHandle, spawn};
+use std::time::Duration;
 
-enum StopWrap<T> {
-    Stop,
-    Wrap(T),
+struct Queue<M, R> {
+    heap: BinaryHeap<Prioritized<Envelope<M, R>>>,
+    next_seq: usize,
+    stopping: bool, // set on shutdown, no more messages are accepted and the thread exits once the queue is empty
+    stopped: bool,  // set by the supervisor, the following messages are discarded
 }
 
-pub struct Looper<M>
+pub struct Looper<M, R = ()>
 where
     M: Send,
+    R: Send,
 {
-    queue: Arc<(Mutex<VecDeque<StopWrap<M>>>, Condvar)>,
-    thread: Option<JoinHandle<()>>,
+    queue: Arc<(Mutex<Queue<M, R>>, Condvar)>,
+    capacity: Option<usize>,
+    thread: Mutex<Option<JoinHandle<()>>>,
 }
 
-impl<M> Looper<M>
+impl<M, R> Looper<M, R>
 where
     M: Send + 'static,
+    R: Send + 'static,
 {
-    pub fn new<P, C>(mut process: P, cleanup: C) -> Self
+    pub fn new<P, C>(process: P, cleanup: C) -> Self
     where
-        P: FnMut(M) -> () + Send + 'static,
-        C: FnOnce() -> () + Send + 'static,
+        P: FnMut(M) -> R + Send + 'static,
+        C: FnOnce() + Send + 'static,
     {
-        let queue = Arc::new((Mutex::new(VecDeque::<StopWrap<M>>::new()), Condvar::new()));
+        Self::build(None, Supervisor::skipping(process), cleanup)
+    }
+
+    // `send` blocks while `capacity` messages are waiting, `try_send` fails instead
+    pub fn with_capacity<P, C>(capacity: usize, process: P, cleanup: C) -> Self
+    where
+        P: FnMut(M) -> R + Send + 'static,
+        C: FnOnce() + Send + 'static,
+    {
+        assert!(
+            capacity > 0,
+            "A bounded looper needs room for at least one message"
+        );
+        Self::build(Some(capacity), Supervisor::skipping(process), cleanup)
+    }
+
+    // `cleanup` runs exactly once, whatever the supervisor decides about a failure
+    pub fn supervised<C>(capacity: Option<usize>, supervisor: Supervisor<M, R>, cleanup: C) -> Self
+    where
+        C: FnOnce() + Send + 'static,
+    {
+        assert!(
+            capacity != Some(0),
+            "A bounded looper needs room for at least one message"
+        );
+        Self::build(capacity, supervisor, cleanup)
+    }
+
+    fn build<C>(capacity: Option<usize>, mut supervisor: Supervisor<M, R>, cleanup: C) -> Self
+    where
+        C: FnOnce() + Send + 'static,
+    {
+        let queue = Arc::new((
+            Mutex::new(Queue {
+                heap: BinaryHeap::new(),
+                next_seq: 0,
+                stopping: false,
+                stopped: false,
+            }),
+            Condvar::new(),
+        ));
         let arc_clone = queue.clone();
         let thread = spawn(move || {
             let mut guard = arc_clone.0.lock().unwrap();
             loop {
-                guard = arc_clone.1.wait_while(guard, |g| g.len() == 0).unwrap();
-                match guard.pop_front().unwrap() {
-                    StopWrap::Wrap(message) => {
-                        process(message);
+                guard = arc_clone
+                    .1
+                    .wait_while(guard, |q| q.heap.is_empty() && !q.stopping)
+                    .unwrap();
+                match guard.heap.pop() {
+                    Some(prioritized) => {
+                        arc_clone.1.notify_all(); // room for a blocked sender
+                        drop(guard);
+                        let keep_going = supervisor.deliver(prioritized.message);
+                        guard = arc_clone.0.lock().unwrap();
+                        if !keep_going {
+                            guard.stopped = true;
+                            guard.heap.clear(); // pending callers are told the looper stopped
+                            arc_clone.1.notify_all();
+                            break;
+                        }
                     }
-                    StopWrap::Stop => {
+                    None => {
                         break;
                     }
                 }
             }
+            drop(guard);
             cleanup();
         });
         Self {
             queue,
-            thread: Some(thread),
+            capacity,
+            thread: Mutex::new(Some(thread)),
         }
     }
 
     pub fn send(&self, message: M) {
+        self.send_with_priority(message, 0);
+    }
+
+    pub fn send_with_priority(&self, message: M, priority: Priority) {
+        self.enqueue((message, None), priority);
+    }
+
+    // the result of `process` is routed back through the handle
+    pub fn call(&self, message: M) -> ReplyHandle<R> {
+        self.call_with_priority(message, 0)
+    }
+
+    pub fn call_with_priority(&self, message: M, priority: Priority) -> ReplyHandle<R> {
+        let (reply, handle) = reply_channel();
+        self.enqueue((message, Some(reply)), priority);
+        handle
+    }
+
+    fn enqueue(&self, envelope: Envelope<M, R>, priority: Priority) {
         let mut lock = self.queue.0.lock().unwrap();
-        lock.push_back(StopWrap::Wrap(message));
-        self.queue.1.notify_one();
+        lock = self
+            .queue
+            .1
+            .wait_while(lock, |q| !q.stopping && !q.stopped && self.is_full(q))
+            .unwrap();
+        if lock.stopping || lock.stopped {
+            return; // a waiting caller finds out through its handle
+        }
+        Self::push(&mut lock, envelope, priority);
+        self.queue.1.notify_all();
+    }
+
+    // the message is given back if the queue is full or the looper has been stopped or shut down
+    pub fn try_send(&self, message: M) -> Result<(), M> {
+        self.try_send_with_priority(message, 0)
+    }
+
+    pub fn try_send_with_priority(&self, message: M, priority: Priority) -> Result<(), M> {
+        let mut lock = self.queue.0.lock().unwrap();
+        if lock.stopping || lock.stopped || self.is_full(&lock) {
+            return Err(message);
+        }
+        Self::push(&mut lock, (message, None), priority);
+        self.queue.1.notify_all();
+        Ok(())
+    }
+
+    fn is_full(&self, queue: &Queue<M, R>) -> bool {
+        self.capacity
+            .is_some_and(|capacity| queue.heap.len() >= capacity)
+    }
+
+    fn push(queue: &mut Queue<M, R>, message: Envelope<M, R>, priority: Priority) {
+        let seq = queue.next_seq;
+        queue.next_seq += 1;
+        queue.heap.push(Prioritized {
+            priority,
+            seq,
+            message,
+        });
     }
 }
 
-impl<M> Drop for Looper<M>
+impl<M, R> Looper<M, R>
 where
     M: Send,
+    R: Send,
 {
-    fn drop(&mut self) {
-        {
-            let mut guard = self.queue.0.lock().unwrap();
-            guard.push_back(StopWrap::Stop);
-            self.queue.1.notify_one();
+    // the following messages are rejected, returns once the thread has run `cleanup`
+    pub fn shutdown(&self, mode: Shutdown) -> Vec<M> {
+        match mode {
+            Shutdown::Drain => self.shutdown_timeout(Duration::MAX),
+            Shutdown::Discard => self.shutdown_timeout(Duration::ZERO),
+        }
+    }
+
+    // drains the queue for at most `timeout`, the messages left are given back
+    // in the order they would have been processed
+    pub fn shutdown_timeout(&self, timeout: Duration) -> Vec<M> {
+        let mut guard = self.queue.0.lock().unwrap();
+        guard.stopping = true;
+        self.queue.1.notify_all();
+        guard = self
+            .queue
+            .1
+            .wait_timeout_while(guard, timeout, |q| !q.heap.is_empty())
+            .unwrap()
+            .0;
+        let discarded = std::iter::from_fn(|| guard.heap.pop())
+            .map(|prioritized| prioritized.message.0)
+            .collect();
+        drop(guard);
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            thread.join().unwrap();
         }
-        self.thread.take().unwrap().join().unwrap();
+        discarded
+    }
+}
+
+impl<M, R> Drop for Looper<M, R>
+where
+    M: Send,
+    R: Send,
+{
+    fn drop(&mut self) {
+        self.shutdown(Shutdown::Drain);
     }
 }
 
@@ -89,3 +239,225 @@ pub fn test() {
         handle.join().unwrap();
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::looper::Supervision;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc::channel;
+    use std::thread::spawn;
+
+    #[test]
+    fn urgent_messages_skip_ahead_of_queued_ones() {
+        let (started_snd, started_rx) = channel::<()>();
+        let (gate_snd, gate_rx) = channel::<()>();
+        let processed = Arc::new(Mutex::new(Vec::new()));
+        let processed_clone = processed.clone();
+        let looper = Looper::new(
+            move |n: usize| {
+                if n == 0 {
+                    started_snd.send(()).unwrap();
+                    gate_rx.recv().unwrap();
+                }
+                processed_clone.lock().unwrap().push(n);
+            },
+            || {},
+        );
+        looper.send(0);
+        started_rx.recv().unwrap();
+        looper.send(1);
+        looper.send_with_priority(2, 5);
+        looper.send(3);
+        gate_snd.send(()).unwrap();
+        drop(looper);
+        assert_eq!(*processed.lock().unwrap(), vec![0, 2, 1, 3]);
+    }
+
+    #[test]
+    fn try_send_fails_when_the_queue_is_full() {
+        let (started_snd, started_rx) = channel::<()>();
+        let (gate_snd, gate_rx) = channel::<()>();
+        let looper = Looper::with_capacity(
+            1,
+            move |n: usize| {
+                if n == 0 {
+                    started_snd.send(()).unwrap();
+                    gate_rx.recv().unwrap();
+                }
+            },
+            || {},
+        );
+        looper.send(0);
+        started_rx.recv().unwrap();
+        assert_eq!(looper.try_send(1), Ok(()));
+        assert_eq!(looper.try_send(2), Err(2));
+        gate_snd.send(()).unwrap();
+    }
+
+    #[test]
+    fn call_returns_the_result_of_process() {
+        let looper = Looper::new(|n: usize| n * 2, || {});
+        let handles = (0..5).map(|n| looper.call(n)).collect::<Vec<_>>();
+        let results = handles
+            .into_iter()
+            .map(|handle| handle.get().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(results, vec![0, 2, 4, 6, 8]);
+    }
+
+    #[test]
+    fn a_panicking_call_reports_to_its_caller_and_the_looper_goes_on() {
+        let looper = Looper::new(
+            |n: usize| {
+                if n == 0 {
+                    panic!("cannot process zero");
+                }
+                10 / n
+            },
+            || {},
+        );
+        let payload = looper.call(0).get().unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"cannot process zero"));
+        assert_eq!(looper.call(5).get().unwrap(), 2);
+    }
+
+    #[test]
+    fn a_panicking_send_does_not_kill_the_looper() {
+        let looper = Looper::new(
+            |n: usize| {
+                if n == 0 {
+                    panic!("cannot process zero");
+                }
+                n
+            },
+            || {},
+        );
+        looper.send(0);
+        assert_eq!(looper.call(1).get().unwrap(), 1);
+    }
+
+    #[test]
+    fn restart_rebuilds_the_state_and_reports_the_failure() {
+        let failures = Arc::new(Mutex::new(Vec::new()));
+        let failures_clone = failures.clone();
+        let supervisor = Supervisor::new(Supervision::Restart, || {
+            let mut count = 0;
+            move |n: usize| {
+                if n == 0 {
+                    panic!("cannot process zero");
+                }
+                count += 1;
+                count
+            }
+        })
+        .on_failure(move |payload| {
+            let message = payload.downcast_ref::<&str>().unwrap().to_string();
+            failures_clone.lock().unwrap().push(message);
+        });
+        let looper = Looper::supervised(None, supervisor, || {});
+        assert_eq!(looper.call(1).get().unwrap(), 1);
+        assert_eq!(looper.call(1).get().unwrap(), 2);
+        assert!(looper.call(0).get().is_err());
+        assert_eq!(looper.call(1).get().unwrap(), 1);
+        assert_eq!(*failures.lock().unwrap(), vec!["cannot process zero"]);
+    }
+
+    #[test]
+    fn stop_discards_the_following_messages_and_cleans_up_once() {
+        let cleanups = Arc::new(AtomicUsize::new(0));
+        let cleanups_clone = cleanups.clone();
+        let supervisor = Supervisor::new(Supervision::Stop, || {
+            |n: usize| {
+                if n == 0 {
+                    panic!("cannot process zero");
+                }
+            }
+        });
+        let looper = Looper::supervised(Some(1), supervisor, move || {
+            cleanups_clone.fetch_add(1, Ordering::SeqCst);
+        });
+        looper.send(0);
+        assert!(looper.call(1).get().is_err());
+        assert_eq!(looper.try_send(2), Err(2));
+        looper.send(3);
+        drop(looper);
+        assert_eq!(cleanups.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn discard_gives_back_the_queued_messages_in_processing_order() {
+        let (started_snd, started_rx) = channel::<()>();
+        let (gate_snd, gate_rx) = channel::<()>();
+        let looper = Arc::new(Looper::new(
+            move |n: usize| {
+                if n == 0 {
+                    started_snd.send(()).unwrap();
+                    gate_rx.recv().unwrap();
+                }
+            },
+            || {},
+        ));
+        looper.send(0);
+        started_rx.recv().unwrap();
+        looper.send(1);
+        looper.send_with_priority(2, 5);
+        looper.send(3);
+        let opener = spawn({
+            let looper = looper.clone();
+            move || {
+                // the message 0 ends only once shutdown has taken the others out of the queue
+                let emptied = || {
+                    let queue = looper.queue.0.lock().unwrap();
+                    queue.stopping && queue.heap.is_empty()
+                };
+                while !emptied() {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                gate_snd.send(()).unwrap();
+            }
+        });
+        assert_eq!(looper.shutdown(Shutdown::Discard), vec![2, 1, 3]);
+        opener.join().unwrap();
+    }
+
+    #[test]
+    fn drain_processes_everything_and_rejects_what_comes_later() {
+        let processed = Arc::new(AtomicUsize::new(0));
+        let cleanups = Arc::new(AtomicUsize::new(0));
+        let (processed_clone, cleanups_clone) = (processed.clone(), cleanups.clone());
+        let looper = Looper::new(
+            move |_: usize| {
+                processed_clone.fetch_add(1, Ordering::SeqCst);
+            },
+            move || {
+                cleanups_clone.fetch_add(1, Ordering::SeqCst);
+            },
+        );
+        (0..5).for_each(|n| looper.send(n));
+        assert!(looper.shutdown(Shutdown::Drain).is_empty());
+        assert_eq!(processed.load(Ordering::SeqCst), 5);
+        assert_eq!(looper.try_send(5), Err(5));
+        assert!(looper.call(6).get().is_err());
+        assert!(looper.shutdown(Shutdown::Discard).is_empty());
+        drop(looper);
+        assert_eq!(cleanups.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn shutdown_timeout_gives_back_what_could_not_be_processed_in_time() {
+        let processed = Arc::new(AtomicUsize::new(0));
+        let processed_clone = processed.clone();
+        let looper = Looper::new(
+            move |_: usize| {
+                std::thread::sleep(Duration::from_millis(20));
+                processed_clone.fetch_add(1, Ordering::SeqCst);
+            },
+            || {},
+        );
+        (0..10).for_each(|n| looper.send(n));
+        let discarded = looper.shutdown_timeout(Duration::from_millis(50));
+        assert!(!discarded.is_empty());
+        assert_eq!(processed.load(Ordering::SeqCst) + discarded.len(), 10);
+    }
+}
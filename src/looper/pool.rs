@@ -0,0 +1,226 @@
+use std::collections::VecDeque;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{JoinHandle, spawn};
+
+// messages are tagged with their arrival order, so that a worker
+// picks the oldest between the shared queue and its own one
+type Queue<M> = VecDeque<(usize, M)>;
+
+struct State<M, C> {
+    shared: Queue<M>,
+    keyed: Vec<Queue<M>>, // one per worker
+    next_seq: usize,
+    running: usize, // workers still alive, the last one runs the cleanup
+    cleanup: Option<C>,
+    stopping: bool, // set on drop, the workers drain the queues and then exit
+}
+
+impl<M, C> State<M, C> {
+    fn pop(&mut self, worker: usize) -> Option<M> {
+        let shared_seq = self.shared.front().map(|(seq, _)| *seq);
+        let keyed_seq = self.keyed[worker].front().map(|(seq, _)| *seq);
+        let queue = match (shared_seq, keyed_seq) {
+            (Some(shared), Some(keyed)) if shared < keyed => &mut self.shared,
+            (Some(_), None) => &mut self.shared,
+            _ => &mut self.keyed[worker],
+        };
+        queue.pop_front().map(|(_, message)| message)
+    }
+
+    fn is_empty(&self, worker: usize) -> bool {
+        self.shared.is_empty() && self.keyed[worker].is_empty()
+    }
+}
+
+// N threads processing the messages of a single queue: plain messages go to whichever worker
+// is free, messages sent with a key always go to the same worker and keep their order
+pub struct LooperPool<M, C>
+where
+    M: Send,
+    C: FnOnce() + Send,
+{
+    state: Arc<(Mutex<State<M, C>>, Condvar)>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl<M, C> LooperPool<M, C>
+where
+    M: Send + 'static,
+    C: FnOnce() + Send + 'static,
+{
+    pub fn new<P>(size: usize, process: P, cleanup: C) -> Self
+    where
+        P: Fn(M) + Send + Sync + 'static,
+    {
+        assert!(size > 0, "A looper pool needs at least one worker");
+        let state = Arc::new((
+            Mutex::new(State {
+                shared: VecDeque::new(),
+                keyed: (0..size).map(|_| VecDeque::new()).collect(),
+                next_seq: 0,
+                running: size,
+                cleanup: Some(cleanup),
+                stopping: false,
+            }),
+            Condvar::new(),
+        ));
+        let process = Arc::new(process);
+        let workers = (0..size)
+            .map(|worker| {
+                let state = state.clone();
+                let process = process.clone();
+                spawn(move || {
+                    let mut guard = state.0.lock().unwrap();
+                    loop {
+                        guard = state
+                            .1
+                            .wait_while(guard, |s| s.is_empty(worker) && !s.stopping)
+                            .unwrap();
+                        let Some(message) = guard.pop(worker) else {
+                            break;
+                        };
+                        drop(guard);
+                        // a failing message is skipped, like in a plain looper
+                        let _ = catch_unwind(AssertUnwindSafe(|| process(message)));
+                        guard = state.0.lock().unwrap();
+                    }
+                    guard.running -= 1;
+                    if guard.running == 0 {
+                        let cleanup = guard.cleanup.take().unwrap();
+                        drop(guard);
+                        cleanup();
+                    }
+                })
+            })
+            .collect();
+        Self { state, workers }
+    }
+
+    pub fn size(&self) -> usize {
+        self.workers.len()
+    }
+
+    pub fn send(&self, message: M) {
+        let mut state = self.state.0.lock().unwrap();
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.shared.push_back((seq, message));
+        self.state.1.notify_all();
+    }
+
+    // messages with the same key are processed one at a time, in the order they were sent
+    pub fn send_keyed<K: Hash + ?Sized>(&self, key: &K, message: M) {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let worker = (hasher.finish() % self.size() as u64) as usize;
+        let mut state = self.state.0.lock().unwrap();
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.keyed[worker].push_back((seq, message));
+        self.state.1.notify_all();
+    }
+}
+
+impl<M, C> Drop for LooperPool<M, C>
+where
+    M: Send,
+    C: FnOnce() + Send,
+{
+    fn drop(&mut self) {
+        {
+            let mut guard = self.state.0.lock().unwrap();
+            guard.stopping = true;
+            self.state.1.notify_all();
+        }
+        self.workers
+            .drain(..)
+            .for_each(|worker| worker.join().unwrap());
+    }
+}
+
+pub fn test() {
+    let pool = Arc::new(LooperPool::new(
+        3,
+        |(sensor, reading): (&str, usize)| println!("Sensor {sensor} read {reading}"),
+        || println!("Cleaning up the pool"),
+    ));
+    let handles = ["kitchen", "garage", "garden"]
+        .into_iter()
+        .map(|sensor| {
+            let pool = pool.clone();
+            spawn(move || {
+                (0..5).for_each(|reading| pool.send_keyed(sensor, (sensor, reading)));
+            })
+        })
+        .collect::<Vec<_>>();
+    handles
+        .into_iter()
+        .for_each(|handle| handle.join().unwrap());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Barrier;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn plain_messages_are_spread_over_the_workers() {
+        // every worker has to take a message for the barrier to open
+        let barrier = Arc::new(Barrier::new(3));
+        let barrier_clone = barrier.clone();
+        let pool = LooperPool::new(
+            3,
+            move |_: usize| {
+                barrier_clone.wait();
+            },
+            || {},
+        );
+        (0..3).for_each(|n| pool.send(n));
+        drop(pool);
+    }
+
+    #[test]
+    fn keyed_messages_keep_their_order() {
+        let processed = Arc::new(Mutex::new(HashMap::<usize, Vec<usize>>::new()));
+        let processed_clone = processed.clone();
+        let pool = LooperPool::new(
+            4,
+            move |(key, n): (usize, usize)| {
+                processed_clone
+                    .lock()
+                    .unwrap()
+                    .entry(key)
+                    .or_default()
+                    .push(n);
+            },
+            || {},
+        );
+        (0..100).for_each(|n| pool.send_keyed(&(n % 5), (n % 5, n)));
+        drop(pool);
+        processed.lock().unwrap().iter().for_each(|(key, values)| {
+            let expected = (0..100).filter(|n| n % 5 == *key).collect::<Vec<_>>();
+            assert_eq!(*values, expected);
+        });
+    }
+
+    #[test]
+    fn cleanup_runs_once_after_every_message() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let (count_clone, count_check) = (count.clone(), count.clone());
+        let pool = LooperPool::new(
+            4,
+            move |_: usize| {
+                count_clone.fetch_add(1, Ordering::SeqCst);
+            },
+            move || assert_eq!(count_check.swap(usize::MAX, Ordering::SeqCst), 50),
+        );
+        (0..50).for_each(|n| pool.send(n));
+        drop(pool);
+        assert_eq!(count.load(Ordering::SeqCst), usize::MAX);
+    }
+}
@@ -0,0 +1,3 @@
+// not an exam track, unlike the other modules: a cyclic barrier whose parties can register and
+// deregister while it runs, after java.util.concurrent.Phaser
+pub mod mutex;
@@ -0,0 +1,221 @@
+use rand::Rng;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::thread::sleep;
+use std::time::Duration;
+
+const N_THREADS: usize = 4;
+
+// called by the last party to arrive, with the phase just completed and the parties still
+// registered; returning true terminates the phaser
+type OnAdvance = Box<dyn Fn(usize, usize) -> bool + Send + Sync>;
+
+// the generation counting is the one of ranking_barrier::mutex, with a variable number of parties
+struct State {
+    phase: usize,
+    registered: usize,
+    arrived: usize,
+    terminated: bool,
+}
+
+// every method returns None once the phaser is terminated
+pub struct Phaser {
+    state: Mutex<State>,
+    cv: Condvar,
+    on_advance: OnAdvance,
+}
+
+impl Phaser {
+    // terminates when every party has deregistered
+    pub fn new(parties: usize) -> Arc<Self> {
+        Self::with_on_advance(parties, |_, registered| registered == 0)
+    }
+
+    // the hook runs while the phaser is locked, it must not call the phaser back
+    pub fn with_on_advance<F>(parties: usize, on_advance: F) -> Arc<Self>
+    where
+        F: Fn(usize, usize) -> bool + Send + Sync + 'static,
+    {
+        Arc::new(Phaser {
+            state: Mutex::new(State {
+                phase: 0,
+                registered: parties,
+                arrived: 0,
+                terminated: false,
+            }),
+            cv: Condvar::new(),
+            on_advance: Box::new(on_advance),
+        })
+    }
+
+    // the new party takes part in the current phase
+    pub fn register(&self) -> Option<usize> {
+        let mut lock = self.state.lock().unwrap();
+        if lock.terminated {
+            return None;
+        }
+        lock.registered += 1;
+        Some(lock.phase)
+    }
+
+    // returns the phase the party arrived at, without waiting for the others
+    pub fn arrive(&self) -> Option<usize> {
+        self.arrive_and(false)
+    }
+
+    pub fn arrive_and_deregister(&self) -> Option<usize> {
+        self.arrive_and(true)
+    }
+
+    // returns the new phase
+    pub fn arrive_and_await_advance(&self) -> Option<usize> {
+        let phase = self.arrive()?;
+        self.await_advance(phase)
+    }
+
+    // waits for `phase` to be completed and returns the new phase, right away if it is over already
+    pub fn await_advance(&self, phase: usize) -> Option<usize> {
+        let lock = self.state.lock().unwrap();
+        let lock = self
+            .cv
+            .wait_while(lock, |s| !s.terminated && s.phase == phase)
+            .unwrap();
+        (!lock.terminated).then_some(lock.phase)
+    }
+
+    pub fn phase(&self) -> Option<usize> {
+        let lock = self.state.lock().unwrap();
+        (!lock.terminated).then_some(lock.phase)
+    }
+
+    pub fn registered_parties(&self) -> usize {
+        self.state.lock().unwrap().registered
+    }
+
+    pub fn arrived_parties(&self) -> usize {
+        self.state.lock().unwrap().arrived
+    }
+
+    pub fn is_terminated(&self) -> bool {
+        self.state.lock().unwrap().terminated
+    }
+
+    fn arrive_and(&self, deregister: bool) -> Option<usize> {
+        let mut lock = self.state.lock().unwrap();
+        if lock.terminated {
+            return None;
+        }
+        assert!(
+            lock.arrived < lock.registered,
+            "More arrivals than registered parties"
+        );
+        let phase = lock.phase;
+        if deregister {
+            lock.registered -= 1;
+        } else {
+            lock.arrived += 1;
+        }
+        if lock.arrived == lock.registered {
+            self.advance(&mut lock);
+        }
+        Some(phase)
+    }
+
+    fn advance(&self, state: &mut State) {
+        state.terminated = (self.on_advance)(state.phase, state.registered);
+        state.phase += 1;
+        state.arrived = 0;
+        self.cv.notify_all();
+    }
+}
+
+pub fn test() {
+    let phaser = Phaser::with_on_advance(1, |phase, registered| {
+        println!("Phase {phase} completed, {registered} parties left");
+        registered == 0
+    });
+
+    let mut handles = vec![];
+
+    for i in 0..N_THREADS {
+        phaser.register();
+        handles.push(thread::spawn({
+            let phaser = phaser.clone();
+            move || {
+                // thread i leaves after i + 1 phases
+                for _ in 0..i {
+                    sleep(Duration::from_millis(rand::rng().random_range(100..500)));
+                    let phase = phaser.arrive_and_await_advance();
+                    println!("Thread {i} moves on to phase {phase:?}");
+                }
+                sleep(Duration::from_millis(rand::rng().random_range(100..500)));
+                phaser.arrive_and_deregister();
+                println!("Thread {i} leaves");
+            }
+        }))
+    }
+    // the main thread was registered at construction, it only takes part in the first phase
+    phaser.arrive_and_deregister();
+
+    for h in handles {
+        h.join().unwrap();
+    }
+    println!("Terminated: {}", phaser.is_terminated());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arrive_does_not_wait_for_the_others() {
+        let phaser = Phaser::new(2);
+        assert_eq!(phaser.arrive(), Some(0));
+        assert_eq!(phaser.arrived_parties(), 1);
+        assert_eq!(phaser.phase(), Some(0));
+        assert_eq!(phaser.arrive(), Some(0));
+        assert_eq!(phaser.phase(), Some(1));
+        assert_eq!(phaser.arrived_parties(), 0);
+        // the phase is over already
+        assert_eq!(phaser.await_advance(0), Some(1));
+    }
+
+    #[test]
+    fn parties_can_join_and_leave_between_phases() {
+        let phaser = Phaser::new(1);
+        assert_eq!(phaser.register(), Some(0));
+        let worker = thread::spawn({
+            let phaser = phaser.clone();
+            move || {
+                let phase = phaser.arrive_and_await_advance();
+                phaser.arrive_and_deregister();
+                phase
+            }
+        });
+        assert_eq!(phaser.arrive_and_await_advance(), Some(1));
+        assert_eq!(worker.join().unwrap(), Some(1));
+        // alone now, the main thread completes the phases by itself
+        assert_eq!(phaser.registered_parties(), 1);
+        assert_eq!(phaser.arrive_and_await_advance(), Some(2));
+        assert_eq!(phaser.arrive_and_deregister(), Some(2));
+        assert!(phaser.is_terminated());
+        assert_eq!(phaser.register(), None);
+    }
+
+    #[test]
+    fn on_advance_can_terminate_the_phaser() {
+        let phaser = Phaser::with_on_advance(2, |phase, _| phase == 1);
+        let waiter = thread::spawn({
+            let phaser = phaser.clone();
+            move || {
+                let first = phaser.arrive_and_await_advance();
+                let second = phaser.arrive_and_await_advance();
+                (first, second)
+            }
+        });
+        assert_eq!(phaser.arrive_and_await_advance(), Some(1));
+        assert_eq!(phaser.arrive_and_await_advance(), None);
+        assert_eq!(waiter.join().unwrap(), (Some(1), None));
+        assert_eq!(phaser.phase(), None);
+    }
+}
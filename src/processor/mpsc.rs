@@ -0,0 +1,145 @@
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::sync::mpsc::{Sender, channel};
+use std::sync::{Arc, Mutex};
+use std::thread::{JoinHandle, spawn};
+
+pub struct Processor<T: Send> {
+    sender: Mutex<Option<Sender<T>>>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl<T: Send + 'static> Processor<T> {
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(T) + Send + 'static,
+    {
+        let (sender, receiver) = channel::<T>();
+        let thread = spawn(move || {
+            // a panicking item is skipped, the following ones are still processed
+            while let Ok(item) = receiver.recv() {
+                let _ = catch_unwind(AssertUnwindSafe(|| f(item)));
+            }
+        });
+        Self {
+            sender: Mutex::new(Some(sender)),
+            thread: Mutex::new(Some(thread)),
+        }
+    }
+
+    // after close the item is given back to the producer
+    pub fn send(&self, item: T) -> Result<(), T> {
+        match self.sender.lock().unwrap().as_ref() {
+            Some(sender) => sender.send(item).map_err(|e| e.0),
+            None => Err(item),
+        }
+    }
+
+    pub fn close(&self) {
+        self.sender.lock().unwrap().take();
+        // the consumer leaves its loop only once the channel is drained
+        let mut thread = self.thread.lock().unwrap();
+        if let Some(handle) = thread.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl<T: Send> Drop for Processor<T> {
+    fn drop(&mut self) {
+        self.sender.get_mut().unwrap().take();
+        if let Some(handle) = self.thread.get_mut().unwrap().take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+pub fn test() {
+    let processor = Arc::new(Processor::new(|(producer, n): (usize, usize)| {
+        println!("Processing item {n} from producer {producer}")
+    }));
+    let handles = (0..3)
+        .map(|producer| {
+            let processor = processor.clone();
+            spawn(move || {
+                (0..5).for_each(|n| processor.send((producer, n)).unwrap());
+            })
+        })
+        .collect::<Vec<_>>();
+    handles
+        .into_iter()
+        .for_each(|handle| handle.join().unwrap());
+    processor.close();
+    println!("All items processed");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    #[test]
+    fn items_are_processed_in_order() {
+        let processed = Arc::new(Mutex::new(Vec::new()));
+        let processed_clone = processed.clone();
+        let processor = Processor::new(move |n: usize| processed_clone.lock().unwrap().push(n));
+        (0..10).for_each(|n| processor.send(n).unwrap());
+        processor.close();
+        assert_eq!(*processed.lock().unwrap(), (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn close_waits_for_the_processing_to_complete() {
+        let processed = Arc::new(Mutex::new(0));
+        let processed_clone = processed.clone();
+        let processor = Processor::new(move |_: ()| {
+            sleep(Duration::from_millis(10));
+            *processed_clone.lock().unwrap() += 1;
+        });
+        (0..5).for_each(|_| processor.send(()).unwrap());
+        processor.close();
+        assert_eq!(*processed.lock().unwrap(), 5);
+    }
+
+    #[test]
+    fn send_after_close_returns_an_error() {
+        let processor = Processor::new(|_: usize| {});
+        processor.close();
+        assert_eq!(processor.send(1), Err(1));
+    }
+
+    #[test]
+    fn more_threads_may_send_data() {
+        let processed = Arc::new(Mutex::new(Vec::new()));
+        let processed_clone = processed.clone();
+        let processor = Processor::new(move |n: usize| processed_clone.lock().unwrap().push(n));
+        std::thread::scope(|s| {
+            s.spawn(|| (0..5).for_each(|n| processor.send(n).unwrap()));
+            s.spawn(|| (5..10).for_each(|n| processor.send(n).unwrap()));
+        });
+        processor.close();
+        let mut processed = processed.lock().unwrap().clone();
+        processed.sort();
+        assert_eq!(processed, (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn a_panicking_item_is_skipped() {
+        let processed = Arc::new(Mutex::new(Vec::new()));
+        let processed_clone = processed.clone();
+        let processor = Processor::new(move |n: usize| {
+            assert_ne!(n, 1, "item 1 can't be processed");
+            processed_clone.lock().unwrap().push(n);
+        });
+        (0..3).for_each(|n| processor.send(n).unwrap());
+        processor.close();
+        assert_eq!(*processed.lock().unwrap(), [0, 2]);
+    }
+
+    #[test]
+    fn a_processor_shuts_down_cleanly() {
+        {
+            let _processor = Processor::new(|_: usize| {});
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::VecDeque;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{JoinHandle, spawn};
+
+struct State<T> {
+    queue: VecDeque<T>,
+    open: bool,
+    busy: bool, // the consumer is processing an item outside of the lock
+}
+
+pub struct Processor<T: Send> {
+    state: Arc<(Mutex<State<T>>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl<T: Send + 'static> Processor<T> {
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(T) + Send + 'static,
+    {
+        let state = Arc::new((
+            Mutex::new(State {
+                queue: VecDeque::new(),
+                open: true,
+                busy: false,
+            }),
+            Condvar::new(),
+        ));
+        let arc_clone = state.clone();
+        let thread = spawn(move || {
+            let (mutex, condvar) = &*arc_clone;
+            let mut guard = mutex.lock().unwrap();
+            loop {
+                guard = condvar
+                    .wait_while(guard, |s| s.queue.is_empty() && s.open)
+                    .unwrap();
+                let Some(item) = guard.queue.pop_front() else {
+                    break; // closed and drained
+                };
+                guard.busy = true;
+                drop(guard);
+                // a panicking item is skipped, close would wait forever for a dead consumer
+                let _ = catch_unwind(AssertUnwindSafe(|| f(item)));
+                guard = mutex.lock().unwrap();
+                guard.busy = false;
+                condvar.notify_all();
+            }
+        });
+        Self {
+            state,
+            thread: Some(thread),
+        }
+    }
+
+    // after close the item is given back to the producer
+    pub fn send(&self, item: T) -> Result<(), T> {
+        let mut guard = self.state.0.lock().unwrap();
+        if !guard.open {
+            return Err(item);
+        }
+        guard.queue.push_back(item);
+        self.state.1.notify_all();
+        Ok(())
+    }
+
+    pub fn close(&self) {
+        let (mutex, condvar) = &*self.state;
+        let mut guard = mutex.lock().unwrap();
+        guard.open = false;
+        condvar.notify_all();
+        drop(
+            condvar
+                .wait_while(guard, |s| !s.queue.is_empty() || s.busy)
+                .unwrap(),
+        );
+    }
+}
+
+impl<T: Send> Drop for Processor<T> {
+    fn drop(&mut self) {
+        {
+            let mut guard = self.state.0.lock().unwrap();
+            guard.open = false;
+            self.state.1.notify_all();
+        }
+        let _ = self.thread.take().unwrap().join();
+    }
+}
+
+pub fn test() {
+    let processor = Arc::new(Processor::new(|(producer, n): (usize, usize)| {
+        println!("Processing item {n} from producer {producer}")
+    }));
+    let handles = (0..3)
+        .map(|producer| {
+            let processor = processor.clone();
+            spawn(move || {
+                (0..5).for_each(|n| processor.send((producer, n)).unwrap());
+            })
+        })
+        .collect::<Vec<_>>();
+    handles
+        .into_iter()
+        .for_each(|handle| handle.join().unwrap());
+    processor.close();
+    println!("All items processed");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    #[test]
+    fn items_are_processed_in_order() {
+        let processed = Arc::new(Mutex::new(Vec::new()));
+        let processed_clone = processed.clone();
+        let processor = Processor::new(move |n: usize| processed_clone.lock().unwrap().push(n));
+        (0..10).for_each(|n| processor.send(n).unwrap());
+        processor.close();
+        assert_eq!(*processed.lock().unwrap(), (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn close_waits_for_the_processing_to_complete() {
+        let processed = Arc::new(Mutex::new(0));
+        let processed_clone = processed.clone();
+        let processor = Processor::new(move |_: ()| {
+            sleep(Duration::from_millis(10));
+            *processed_clone.lock().unwrap() += 1;
+        });
+        (0..5).for_each(|_| processor.send(()).unwrap());
+        processor.close();
+        assert_eq!(*processed.lock().unwrap(), 5);
+    }
+
+    #[test]
+    fn send_after_close_returns_an_error() {
+        let processor = Processor::new(|_: usize| {});
+        processor.close();
+        assert_eq!(processor.send(1), Err(1));
+    }
+
+    #[test]
+    fn more_threads_may_send_data() {
+        let processed = Arc::new(Mutex::new(Vec::new()));
+        let processed_clone = processed.clone();
+        let processor = Processor::new(move |n: usize| processed_clone.lock().unwrap().push(n));
+        std::thread::scope(|s| {
+            s.spawn(|| (0..5).for_each(|n| processor.send(n).unwrap()));
+            s.spawn(|| (5..10).for_each(|n| processor.send(n).unwrap()));
+        });
+        processor.close();
+        let mut processed = processed.lock().unwrap().clone();
+        processed.sort();
+        assert_eq!(processed, (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn a_panicking_item_is_skipped() {
+        let processed = Arc::new(Mutex::new(Vec::new()));
+        let processed_clone = processed.clone();
+        let processor = Processor::new(move |n: usize| {
+            assert_ne!(n, 1, "item 1 can't be processed");
+            processed_clone.lock().unwrap().push(n);
+        });
+        (0..3).for_each(|n| processor.send(n).unwrap());
+        processor.close();
+        assert_eq!(*processed.lock().unwrap(), [0, 2]);
+    }
+
+    #[test]
+    fn a_processor_shuts_down_cleanly() {
+        {
+            let _processor = Processor::new(|_: usize| {});
+        }
+    }
+}
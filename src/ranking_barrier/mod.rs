@@ -1,2 +1,32 @@
+use std::time::Instant;
+
 pub mod mpsc;
 pub mod mutex;
+pub mod weighted;
+
+// what every party gets back from a barrier that opened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RankingResult {
+    pub rank: usize,       // 1 for the first to arrive
+    pub generation: usize, // how many times the barrier opened before this one
+    pub arrived_at: Instant,
+}
+
+impl RankingResult {
+    // exactly one party per generation is the leader, the first to arrive
+    pub fn is_leader(&self) -> bool {
+        self.rank == 1
+    }
+}
+
+// a party timed out or the barrier action panicked: everyone waiting gets this error,
+// and so does everyone arriving later, until the barrier is reset
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BrokenBarrier;
+
+// a barrier needs at least two parties, one alone would never have to wait
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TooFewParties;
+
+// run by the barrier once per generation, when the last party arrives
+type BarrierAction = Box<dyn FnMut() + Send>;
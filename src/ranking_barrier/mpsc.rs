@@ -1,44 +1,138 @@
-use std::sync::mpsc::{Sender, channel};
+use super::{BarrierAction, BrokenBarrier, RankingResult, TooFewParties};
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, channel};
 use std::thread::{JoinHandle, spawn};
+use std::time::{Duration, Instant};
 
-type WaitRequest = Sender<usize>;
+type Outcome = Result<RankingResult, BrokenBarrier>;
+type Reply = Sender<Outcome>;
+
+enum Request {
+    Wait(usize, Reply), // every wait has an id, to tell which one timed out
+    TimedOut(usize),
+    Reset,
+}
 
 pub struct RankingBarrier {
-    sender: Option<Sender<WaitRequest>>,
+    sender: Option<Sender<Request>>,
     thread: Option<JoinHandle<()>>,
+    next_id: AtomicUsize,
 }
 
 impl RankingBarrier {
-    pub fn new(n: usize) -> Result<Self, ()> {
+    pub fn new(n: usize) -> Result<Arc<Self>, TooFewParties> {
+        Self::build(n, None).map(Arc::new)
+    }
+
+    // the action is run once per generation, before anybody leaves
+    pub fn with_action<F>(n: usize, action: F) -> Result<Arc<Self>, TooFewParties>
+    where
+        F: FnMut() + Send + 'static,
+    {
+        Self::build(n, Some(Box::new(action))).map(Arc::new)
+    }
+
+    fn build(n: usize, mut action: Option<BarrierAction>) -> Result<Self, TooFewParties> {
         if n < 2 {
-            return Err(());
+            return Err(TooFewParties);
         }
-        let (snd, rx) = channel::<WaitRequest>();
+        let (snd, rx) = channel::<Request>();
         let sender = Some(snd);
 
         Ok(Self {
             sender,
             thread: Some(spawn(move || {
-                let mut queue = Vec::<Sender<usize>>::with_capacity(n);
-                while let Ok(snd_rank) = rx.recv() {
-                    queue.push(snd_rank);
-                    if queue.len() == n {
-                        queue
-                            .iter()
-                            .enumerate()
-                            .for_each(|(index, snd_rank)| snd_rank.send(index + 1).unwrap());
-                        queue.clear();
+                let mut queue = Vec::<(usize, Reply, Instant)>::with_capacity(n);
+                let mut generation = 0;
+                let mut broken = false;
+                while let Ok(request) = rx.recv() {
+                    match request {
+                        Request::Wait(_, snd_rank) if broken => {
+                            let _ = snd_rank.send(Err(BrokenBarrier));
+                        }
+                        Request::Wait(id, snd_rank) => {
+                            queue.push((id, snd_rank, Instant::now()));
+                            if queue.len() < n {
+                                continue;
+                            }
+                            let failed = action
+                                .as_mut()
+                                .is_some_and(|a| catch_unwind(AssertUnwindSafe(a)).is_err());
+                            if failed {
+                                broken = true;
+                                fail(&mut queue);
+                                continue;
+                            }
+                            queue.drain(..).enumerate().for_each(
+                                |(index, (_, snd_rank, arrived_at))| {
+                                    let _ = snd_rank.send(Ok(RankingResult {
+                                        rank: index + 1,
+                                        generation,
+                                        arrived_at,
+                                    }));
+                                },
+                            );
+                            generation += 1;
+                        }
+                        // it might have been answered in the meantime, then nothing is broken
+                        Request::TimedOut(id) if queue.iter().any(|(i, _, _)| *i == id) => {
+                            broken = true;
+                            fail(&mut queue);
+                        }
+                        Request::TimedOut(_) => {}
+                        Request::Reset => {
+                            broken = false;
+                            fail(&mut queue);
+                        }
                     }
                 }
             })),
+            next_id: AtomicUsize::new(0),
         })
     }
 
-    pub fn wait(&self) {
-        let (snd_rank, rx_rank) = channel::<usize>();
-        self.sender.as_ref().unwrap().send(snd_rank).unwrap();
-        rx_rank.recv().unwrap();
+    pub fn wait(&self) -> Outcome {
+        let (_, rx_rank) = self.request_wait();
+        rx_rank.recv().unwrap()
+    }
+
+    // giving up breaks the barrier for everybody
+    pub fn wait_timeout(&self, timeout: Duration) -> Outcome {
+        let (id, rx_rank) = self.request_wait();
+        match rx_rank.recv_timeout(timeout) {
+            Err(RecvTimeoutError::Timeout) => {
+                self.request(Request::TimedOut(id));
+                // either the barrier opened just before, or this is the error
+                rx_rank.recv().unwrap()
+            }
+            result => result.unwrap(),
+        }
+    }
+
+    // the parties still waiting get an error, the ones arriving next find the barrier as new
+    pub fn reset(&self) {
+        self.request(Request::Reset);
+    }
+
+    fn request_wait(&self) -> (usize, Receiver<Outcome>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (snd_rank, rx_rank) = channel();
+        self.request(Request::Wait(id, snd_rank));
+        (id, rx_rank)
     }
+
+    fn request(&self, request: Request) {
+        self.sender.as_ref().unwrap().send(request).unwrap();
+    }
+}
+
+fn fail(queue: &mut Vec<(usize, Reply, Instant)>) {
+    // a waiter that gave up doesn't listen anymore
+    queue.drain(..).for_each(|(_, snd_rank, _)| {
+        let _ = snd_rank.send(Err(BrokenBarrier));
+    });
 }
 
 impl Drop for RankingBarrier {
@@ -47,3 +141,65 @@ impl Drop for RankingBarrier {
         self.thread.take().unwrap().join().unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_party_gets_its_rank() {
+        let barrier = RankingBarrier::with_action(3, || {}).unwrap();
+        let handles = (0..3)
+            .map(|_| {
+                let barrier = barrier.clone();
+                spawn(move || barrier.wait().unwrap())
+            })
+            .collect::<Vec<_>>();
+        let mut ranks = handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .collect::<Vec<_>>();
+        ranks.sort_by_key(|r| r.rank);
+        assert_eq!(ranks.iter().map(|r| r.rank).collect::<Vec<_>>(), [1, 2, 3]);
+        assert!(ranks[0].is_leader() && !ranks[1].is_leader());
+        assert!(ranks.iter().all(|r| r.generation == 0));
+        assert!(ranks.windows(2).all(|w| w[0].arrived_at <= w[1].arrived_at));
+        let next = (0..2)
+            .map(|_| {
+                let barrier = barrier.clone();
+                spawn(move || barrier.wait().unwrap())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(barrier.wait().unwrap().generation, 1);
+        next.into_iter()
+            .for_each(|h| assert_eq!(h.join().unwrap().generation, 1));
+    }
+
+    #[test]
+    fn a_timeout_breaks_the_barrier_until_reset() {
+        let barrier = RankingBarrier::new(2).unwrap();
+        assert_eq!(
+            barrier.wait_timeout(Duration::from_millis(10)),
+            Err(BrokenBarrier)
+        );
+        assert_eq!(barrier.wait(), Err(BrokenBarrier));
+        barrier.reset();
+        let other = spawn({
+            let barrier = barrier.clone();
+            move || barrier.wait()
+        });
+        assert!(barrier.wait().is_ok());
+        assert!(other.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn a_panicking_action_breaks_the_barrier() {
+        let barrier = RankingBarrier::with_action(2, || panic!("action failed")).unwrap();
+        let other = spawn({
+            let barrier = barrier.clone();
+            move || barrier.wait()
+        });
+        assert_eq!(barrier.wait(), Err(BrokenBarrier));
+        assert_eq!(other.join().unwrap(), Err(BrokenBarrier));
+    }
+}
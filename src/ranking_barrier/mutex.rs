@@ -1,70 +1,143 @@
+use super::{BarrierAction, BrokenBarrier, RankingResult, TooFewParties};
+use std::panic::{AssertUnwindSafe, catch_unwind, resume_unwind};
 use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 
 const N: usize = 5;
 
-#[derive(PartialEq)]
-enum State {
-    Progress,
-    Closure,
+struct State {
+    arrived: usize,    // parties of the current generation that haven't left yet
+    weight: usize,     // every party weighs 1, unless it waits through a WeightedBarrier
+    generation: usize, // bumped every time the barrier opens
+    broken: bool,
+    action: Option<BarrierAction>,
 }
 
-struct RankingBarrier {
+pub struct RankingBarrier {
     n_threads: usize, //no need to protect here because it is wrapped inside an Arc and is never written
-    counter: Mutex<(usize, State)>,
+    state: Mutex<State>,
     cv: Condvar,
 }
 
 impl RankingBarrier {
-    fn new(n_threads: usize) -> Result<Arc<Self>, ()> {
+    pub fn new(n_threads: usize) -> Result<Arc<Self>, TooFewParties> {
+        Self::build(n_threads, None).map(Arc::new)
+    }
+
+    // the action is run by the last party to arrive, before anybody leaves
+    pub fn with_action<F>(n_threads: usize, action: F) -> Result<Arc<Self>, TooFewParties>
+    where
+        F: FnMut() + Send + 'static,
+    {
+        Self::build(n_threads, Some(Box::new(action))).map(Arc::new)
+    }
+
+    pub(super) fn build(
+        n_threads: usize,
+        action: Option<BarrierAction>,
+    ) -> Result<Self, TooFewParties> {
         match n_threads {
-            0..=1 => Err(()),
-            _ => Ok(Arc::new(RankingBarrier {
+            0..=1 => Err(TooFewParties),
+            _ => Ok(RankingBarrier {
                 n_threads,
-                counter: Mutex::new((0, State::Progress)),
+                state: Mutex::new(State {
+                    arrived: 0,
+                    weight: 0,
+                    generation: 0,
+                    broken: false,
+                    action,
+                }),
                 cv: Condvar::new(),
-            })),
+            }),
         }
     }
 
-    pub fn wait(&self, thread_index: usize /*only added for clarity*/) -> usize {
-        let mut lock = self.counter.lock().unwrap();
-
-        lock = self
-            .cv
-            .wait_while(lock, |l| (*l).1 == State::Progress && (*l).0 > 0)
-            .unwrap();
+    pub(super) fn n_threads(&self) -> usize {
+        self.n_threads
+    }
 
-        //arrives here only if state is progress and counter is 0 (everybody out)
-        if (*lock).1 == State::Progress {
-            (*lock).1 = State::Closure;
-            println!();
-            self.cv.notify_all();
-        }
-        (*lock).0 += 1;
+    pub fn wait(&self) -> Result<RankingResult, BrokenBarrier> {
+        self.wait_until(1, None)
+    }
 
-        let ret = (*lock).0;
+    // giving up breaks the barrier for everybody
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<RankingResult, BrokenBarrier> {
+        self.wait_until(1, Some(timeout))
+    }
 
-        println!("Thread {thread_index} comes {}th", ret);
+    // the parties still waiting get an error, the ones arriving next find the barrier as new
+    pub fn reset(&self) {
+        let mut lock = self.state.lock().unwrap();
+        lock.broken = true;
+        self.cv.notify_all();
+        // the waiters have to see the broken state before it is cleared
+        lock = self.cv.wait_while(lock, |s| s.arrived > 0).unwrap();
+        lock.weight = 0;
+        lock.broken = false;
+    }
 
-        while (*lock).0 != self.n_threads && (*lock).1 == State::Closure {
-            lock = self.cv.wait(lock).unwrap();
+    // opens once the weights add up to n_threads, the excess counts towards the next generation
+    pub(super) fn wait_until(
+        &self,
+        weight: usize,
+        timeout: Option<Duration>,
+    ) -> Result<RankingResult, BrokenBarrier> {
+        let mut lock = self.state.lock().unwrap();
+        if lock.broken {
+            return Err(BrokenBarrier);
         }
-
-        if (*lock).1 == State::Closure {
-            (*lock).1 = State::Progress;
-            println!();
+        lock.arrived += 1;
+        lock.weight += weight;
+        let result = RankingResult {
+            rank: lock.arrived,
+            generation: lock.generation,
+            arrived_at: Instant::now(),
+        };
+        let generation = lock.generation;
+
+        if lock.weight >= self.n_threads {
+            if let Some(action) = lock.action.as_mut()
+                && let Err(panic) = catch_unwind(AssertUnwindSafe(action))
+            {
+                self.leave_broken(&mut lock);
+                drop(lock);
+                resume_unwind(panic);
+            }
+            lock.arrived = 0;
+            lock.weight -= self.n_threads;
+            lock.generation += 1;
             self.cv.notify_all();
+            return Ok(result);
         }
 
-        (*lock).0 -= 1;
+        let waiting = |s: &mut State| s.generation == generation && !s.broken;
+        lock = match timeout {
+            Some(timeout) => {
+                let (mut lock, _) = self.cv.wait_timeout_while(lock, timeout, waiting).unwrap();
+                if lock.generation == generation {
+                    lock.broken = true; // no effect if somebody else broke it first
+                }
+                lock
+            }
+            None => self.cv.wait_while(lock, waiting).unwrap(),
+        };
+        // the generation only changes when the barrier opens, a reset waits for the waiters
+        if lock.generation == generation {
+            self.leave_broken(&mut lock);
+            return Err(BrokenBarrier);
+        }
+        Ok(result)
+    }
 
-        println!("Thread {thread_index} returning {}", ret);
-        return ret;
+    fn leave_broken(&self, state: &mut State) {
+        state.broken = true;
+        state.arrived -= 1;
+        self.cv.notify_all();
     }
 }
 
 pub fn test() {
-    let c_barrier = RankingBarrier::new(N)
+    let c_barrier = RankingBarrier::with_action(N, || println!())
         .expect("At least 2 threads are required for the barrier to work properly");
     let mut vt = Vec::new();
 
@@ -73,7 +146,13 @@ pub fn test() {
             let c = c_barrier.clone();
             move || {
                 for _ in 0..3 {
-                    c.wait(i);
+                    match c.wait() {
+                        Ok(r) if r.is_leader() => {
+                            println!("Thread {i} comes 1st and leads round {}", r.generation)
+                        }
+                        Ok(r) => println!("Thread {i} comes {}th", r.rank),
+                        Err(_) => println!("Thread {i} found the barrier broken"),
+                    }
                 }
             }
         }));
@@ -83,3 +162,94 @@ pub fn test() {
         t.join().unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread::spawn;
+
+    #[test]
+    fn one_leader_and_one_action_per_generation() {
+        let actions = Arc::new(AtomicUsize::new(0));
+        let barrier = RankingBarrier::with_action(3, {
+            let actions = actions.clone();
+            move || {
+                actions.fetch_add(1, Ordering::SeqCst);
+            }
+        })
+        .unwrap();
+        let handles = (0..3)
+            .map(|_| {
+                let barrier = barrier.clone();
+                spawn(move || (0..4).map(|_| barrier.wait().unwrap()).collect::<Vec<_>>())
+            })
+            .collect::<Vec<_>>();
+        let results = handles
+            .into_iter()
+            .flat_map(|h| h.join().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(results.iter().filter(|r| r.is_leader()).count(), 4);
+        assert_eq!(actions.load(Ordering::SeqCst), 4);
+        // within a generation the ranks follow the arrival times
+        (0..4).for_each(|generation| {
+            let mut round = results
+                .iter()
+                .filter(|r| r.generation == generation)
+                .collect::<Vec<_>>();
+            round.sort_by_key(|r| r.rank);
+            assert_eq!(round.len(), 3);
+            assert!(round.windows(2).all(|w| w[0].arrived_at <= w[1].arrived_at));
+        });
+    }
+
+    #[test]
+    fn a_barrier_needs_two_parties() {
+        assert!(matches!(RankingBarrier::new(1), Err(TooFewParties)));
+        assert!(RankingBarrier::new(2).is_ok());
+    }
+
+    #[test]
+    fn a_timeout_breaks_the_barrier_until_reset() {
+        let barrier = RankingBarrier::new(3).unwrap();
+        let waiter = spawn({
+            let barrier = barrier.clone();
+            move || barrier.wait()
+        });
+        assert_eq!(
+            barrier.wait_timeout(Duration::from_millis(20)),
+            Err(BrokenBarrier)
+        );
+        assert_eq!(waiter.join().unwrap(), Err(BrokenBarrier));
+        assert_eq!(barrier.wait_timeout(Duration::ZERO), Err(BrokenBarrier));
+
+        barrier.reset();
+        let handles = (0..2)
+            .map(|_| {
+                let barrier = barrier.clone();
+                spawn(move || barrier.wait())
+            })
+            .collect::<Vec<_>>();
+        assert!(barrier.wait().is_ok());
+        handles
+            .into_iter()
+            .for_each(|h| assert!(h.join().unwrap().is_ok()));
+    }
+
+    #[test]
+    fn a_panicking_action_breaks_the_barrier() {
+        let barrier = RankingBarrier::with_action(2, || panic!("action failed")).unwrap();
+        let waiter = spawn({
+            let barrier = barrier.clone();
+            move || barrier.wait()
+        });
+        let last = spawn({
+            let barrier = barrier.clone();
+            move || barrier.wait()
+        });
+        // whoever arrives last runs the action and gets its panic, the other one an error
+        let results = [waiter.join(), last.join()];
+        assert_eq!(results.iter().filter(|r| r.is_err()).count(), 1);
+        assert!(results.iter().any(|r| matches!(r, Ok(Err(BrokenBarrier)))));
+    }
+}
@@ -0,0 +1,126 @@
+use super::mutex::RankingBarrier;
+use super::{BarrierAction, BrokenBarrier, RankingResult, TooFewParties};
+use std::sync::Arc;
+use std::thread::spawn;
+use std::time::Duration;
+
+// a RankingBarrier where a party may count for more than one: it opens when the weights of the
+// parties waiting add up to `total`, and whatever exceeds it is already part of the next generation
+pub struct WeightedBarrier {
+    barrier: RankingBarrier,
+}
+
+impl WeightedBarrier {
+    pub fn new(total: usize) -> Result<Arc<Self>, TooFewParties> {
+        Self::build(total, None)
+    }
+
+    // the action is run by the party that completes the total, before anybody leaves
+    pub fn with_action<F>(total: usize, action: F) -> Result<Arc<Self>, TooFewParties>
+    where
+        F: FnMut() + Send + 'static,
+    {
+        Self::build(total, Some(Box::new(action)))
+    }
+
+    fn build(total: usize, action: Option<BarrierAction>) -> Result<Arc<Self>, TooFewParties> {
+        let barrier = RankingBarrier::build(total, action)?;
+        Ok(Arc::new(WeightedBarrier { barrier }))
+    }
+
+    // the rank is the arrival order, whatever the weight
+    pub fn wait(&self, weight: usize) -> Result<RankingResult, BrokenBarrier> {
+        self.barrier.wait_until(self.check(weight), None)
+    }
+
+    // giving up breaks the barrier for everybody
+    pub fn wait_timeout(
+        &self,
+        weight: usize,
+        timeout: Duration,
+    ) -> Result<RankingResult, BrokenBarrier> {
+        self.barrier.wait_until(self.check(weight), Some(timeout))
+    }
+
+    // the carried over weight is lost too
+    pub fn reset(&self) {
+        self.barrier.reset();
+    }
+
+    // a party can't be heavier than a whole generation
+    fn check(&self, weight: usize) -> usize {
+        assert!(
+            (1..=self.barrier.n_threads()).contains(&weight),
+            "The weight has to be between 1 and the total of the barrier"
+        );
+        weight
+    }
+}
+
+pub fn test() {
+    let barrier = WeightedBarrier::with_action(6, || println!())
+        .expect("The total has to be at least 2 for the barrier to work properly");
+    let handles = (1..=3)
+        .map(|weight| {
+            let barrier = barrier.clone();
+            spawn(move || {
+                for _ in 0..3 {
+                    match barrier.wait(weight) {
+                        Ok(r) => println!(
+                            "Party of weight {weight} comes {}th in generation {}",
+                            r.rank, r.generation
+                        ),
+                        Err(_) => println!("Party of weight {weight} found the barrier broken"),
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    handles
+        .into_iter()
+        .for_each(|handle| handle.join().unwrap());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_when_the_weights_add_up() {
+        let barrier = WeightedBarrier::new(4).unwrap();
+        let light = spawn({
+            let barrier = barrier.clone();
+            move || barrier.wait(1)
+        });
+        // the light party alone isn't enough
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!light.is_finished());
+        let heavy = barrier.wait(3).unwrap();
+        let light = light.join().unwrap().unwrap();
+        assert_eq!((light.rank, heavy.rank), (1, 2));
+        assert!(light.is_leader());
+    }
+
+    #[test]
+    fn the_excess_weight_goes_to_the_next_generation() {
+        let barrier = WeightedBarrier::new(4).unwrap();
+        let first = spawn({
+            let barrier = barrier.clone();
+            move || barrier.wait(3)
+        });
+        std::thread::sleep(Duration::from_millis(20));
+        // 3 + 3 opens the first generation, with 2 left over
+        assert_eq!(barrier.wait(3).unwrap().generation, 0);
+        assert_eq!(first.join().unwrap().unwrap().generation, 0);
+        // 2 more are enough for the second one
+        let result = barrier.wait(2).unwrap();
+        assert_eq!((result.rank, result.generation), (1, 1));
+    }
+
+    #[test]
+    #[should_panic(expected = "The weight has to be between 1 and the total of the barrier")]
+    fn a_party_cannot_outweigh_the_barrier() {
+        let barrier = WeightedBarrier::new(4).unwrap();
+        let _ = barrier.wait(5);
+    }
+}
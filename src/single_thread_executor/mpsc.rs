@@ -0,0 +1,180 @@
+use std::any::Any;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::sync::{Arc, Mutex};
+use std::thread::{JoinHandle, ThreadId, current, spawn};
+
+type Task = Box<dyn FnOnce() + Send + 'static>;
+type TaskResult<R> = Result<R, Box<dyn Any + Send + 'static>>;
+
+pub struct TaskHandle<R>(Receiver<TaskResult<R>>);
+
+impl<R> TaskHandle<R> {
+    // blocks until the task has been executed, a panicking task yields its payload as error
+    pub fn get(self) -> TaskResult<R> {
+        self.0.recv().unwrap() // the worker drains the whole queue before exiting
+    }
+}
+
+pub struct SingleThreadExecutor {
+    sender: Mutex<Option<Sender<Task>>>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+    worker_id: ThreadId,
+}
+
+impl SingleThreadExecutor {
+    pub fn new() -> Self {
+        let (sender, receiver) = channel::<Task>();
+        let thread = spawn(move || {
+            while let Ok(task) = receiver.recv() {
+                task()
+            }
+        });
+        Self {
+            sender: Mutex::new(Some(sender)),
+            worker_id: thread.thread().id(),
+            thread: Mutex::new(Some(thread)),
+        }
+    }
+
+    // the task is given back if the executor has already been closed
+    pub fn submit<F, R>(&self, task: F) -> Result<TaskHandle<R>, F>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let guard = self.sender.lock().unwrap();
+        let Some(sender) = guard.as_ref() else {
+            return Err(task);
+        };
+        let (result_snd, result_rx) = channel::<TaskResult<R>>();
+        sender
+            .send(Box::new(move || {
+                // the handle might have been dropped, nobody is interested in the result then
+                let _ = result_snd.send(catch_unwind(AssertUnwindSafe(task)));
+            }))
+            .unwrap();
+        Ok(TaskHandle(result_rx))
+    }
+
+    pub fn close(&self) {
+        self.sender.lock().unwrap().take();
+    }
+
+    pub fn join(&self) {
+        self.close();
+        if current().id() == self.worker_id {
+            // invoked by a task: the worker can't wait for itself, it stops once the queue is drained
+            return;
+        }
+        let mut thread = self.thread.lock().unwrap();
+        if let Some(handle) = thread.take() {
+            handle.join().unwrap();
+        }
+    }
+}
+
+impl Default for SingleThreadExecutor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for SingleThreadExecutor {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
+
+pub fn test() {
+    let executor = Arc::new(SingleThreadExecutor::new());
+    let handles = (0..3)
+        .map(|n| {
+            let executor_clone = executor.clone();
+            executor
+                .submit(move || {
+                    println!("Task {n} submitting a nested task");
+                    let nested = executor_clone.submit(move || n * 10).ok().unwrap();
+                    (n, nested)
+                })
+                .ok()
+                .unwrap()
+        })
+        .collect::<Vec<_>>();
+    let panicking = executor.submit(|| panic!("Task failure")).ok().unwrap();
+    handles.into_iter().for_each(|handle| {
+        let (n, nested) = handle.get().unwrap();
+        println!("Task {n} nested result: {}", nested.get().unwrap());
+    });
+    println!("Panicking task failed: {}", panicking.get().is_err());
+    executor.join();
+    println!(
+        "Submit after join rejected: {}",
+        executor.submit(|| ()).is_err()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn a_submitted_task_returns_its_result() {
+        let executor = SingleThreadExecutor::new();
+        let handle = executor.submit(|| 21 * 2).ok().unwrap();
+        assert_eq!(handle.get().unwrap(), 42);
+    }
+
+    #[test]
+    fn a_panicking_task_does_not_stop_the_executor() {
+        let executor = SingleThreadExecutor::new();
+        let failing = executor.submit(|| panic!("failure")).ok().unwrap();
+        let succeeding = executor.submit(|| 1).ok().unwrap();
+        assert!(failing.get().is_err());
+        assert_eq!(succeeding.get().unwrap(), 1);
+    }
+
+    #[test]
+    fn tasks_are_executed_in_submission_order() {
+        let executor = SingleThreadExecutor::new();
+        let order = Arc::new(Mutex::new(Vec::new()));
+        (0..5).for_each(|n| {
+            let order = order.clone();
+            let _ = executor.submit(move || order.lock().unwrap().push(n));
+        });
+        executor.join();
+        assert_eq!(*order.lock().unwrap(), vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn submit_after_close_is_rejected() {
+        let executor = SingleThreadExecutor::new();
+        executor.close();
+        assert!(executor.submit(|| ()).is_err());
+    }
+
+    #[test]
+    fn join_waits_for_pending_tasks() {
+        let executor = SingleThreadExecutor::new();
+        let done = Arc::new(Mutex::new(false));
+        let done_clone = done.clone();
+        let _ = executor.submit(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            *done_clone.lock().unwrap() = true;
+        });
+        executor.join();
+        assert!(*done.lock().unwrap());
+    }
+
+    #[test]
+    fn a_task_may_submit_further_tasks() {
+        let executor = Arc::new(SingleThreadExecutor::new());
+        let executor_clone = executor.clone();
+        let outer = executor
+            .submit(move || executor_clone.submit(|| "nested").ok().unwrap())
+            .ok()
+            .unwrap();
+        assert_eq!(outer.get().unwrap().get().unwrap(), "nested");
+    }
+}